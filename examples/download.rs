@@ -7,6 +7,8 @@ use usb::usbio;
 extern crate getopts;
 use getopts::Options;
 
+use std::time::Instant;
+
 fn usage(program: &str, opts: &Options) {
     let brief = format!(
         "Version: {}\nUsage: {} [options]",
@@ -24,6 +26,11 @@ fn main() {
     opts.optopt("", "vid", "Vendor ID", "<hex>");
     opts.optopt("", "pid", "Product ID", "<hex>");
     opts.optopt("", "size", "Size to download", "<size>");
+    opts.optflag(
+        "",
+        "bench",
+        "Compare throughput with a packet, 64 KiB and the default per bulk transfer",
+    );
 
     if args.len() <= 1 {
         usage(&program, &opts);
@@ -60,5 +67,29 @@ fn main() {
         .expect(&format!("Failed to open {}:{}", vid, pid));
 
     let data = vec![0; size];
-    println!("{:?}", device.download(&data));
+    if !matches.opt_present("bench") {
+        download(&mut device, &data);
+        return;
+    }
+
+    // One packet per transfer is how writes went before they were sent in
+    // bursts, so the first run is the baseline to compare the others with
+    let default_size = device.bulk_transfer_size();
+    for transfer_size in &[1, 64 * 1024, default_size] {
+        device.set_bulk_transfer_size(*transfer_size);
+        print!("{:>8} bytes per transfer: ", device.bulk_transfer_size());
+        download(&mut device, &data);
+    }
+}
+
+fn download(device: &mut usbio::UsbDevice, data: &[u8]) {
+    let start = Instant::now();
+    let result = device.download(data);
+    let elapsed = start.elapsed();
+    println!(
+        "{:?} in {:.2?} ({:.2} MiB/s)",
+        result,
+        elapsed,
+        data.len() as f64 / (1024.0 * 1024.0) / elapsed.as_secs_f64()
+    );
 }
@@ -7,6 +7,8 @@ use usb::usbio;
 extern crate getopts;
 use getopts::Options;
 
+use std::time::Duration;
+
 fn usage(program: &str, opts: &Options) {
     let brief = format!(
         "Version: {}\nUsage: {} [options]",
@@ -23,6 +25,11 @@ fn main() {
     opts.optflag("h", "help", "Print help");
     opts.optopt("", "vid", "Vendor ID", "<hex>");
     opts.optopt("", "pid", "Product ID", "<hex>");
+    opts.optflag(
+        "b",
+        "bootloader",
+        "Reboot into the bootloader and wait for it",
+    );
 
     if args.len() <= 1 {
         usage(&program, &opts);
@@ -53,5 +60,10 @@ fn main() {
         .open(vid, pid)
         .expect(&format!("Failed to open {}:{}", vid, pid));
 
+    if matches.opt_present("b") {
+        let device = device.reboot_and_reconnect(&context, Duration::from_secs(30));
+        println!("Rebooted into the bootloader: {:?}", device.map(|_| ()));
+        return;
+    }
     println!("Rebooting: {:?}", device.reboot());
 }
@@ -0,0 +1,221 @@
+//! Asynchronous counterpart of the [`Fastboot`] trait built on Tokio I/O.
+//!
+//! Replies are parsed the same way as in the synchronous implementation, so
+//! both behave identically apart from not blocking a thread per device.
+//!
+//! [`Fastboot`]: ../fastboot/trait.Fastboot.html
+
+use std;
+use std::future::Future;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::fastboot::{
+    check_download_size, check_partition, known_limit, parse_var_number, trace, FastbootError,
+    FbResult, Reply, DEFAULT_MAX_TIMEOUTS, DOWNLOAD_CHUNK_LEN, ERASE_CMD,
+    FB_MAX_ASSEMBLED_REPLY_LEN, FB_MAX_REPLY_LEN, FLASH_CMD, GETVAR_CMD, MAX_DOWNLOAD_SIZE_VAR,
+    REBOOT_CMD,
+};
+use crate::protocol::{self, DOWNLOAD_COMMAND_LEN};
+
+/// Sends a raw command to a client and waits for its first reply.
+///
+/// See [`send_command`] for details.
+///
+/// [`send_command`]: ../fastboot/fn.send_command.html
+pub async fn send_command<T: AsyncFastboot>(io: &mut T, payload: &[u8]) -> FbResult<Reply> {
+    trace("->", payload);
+    io.write_all(payload).await?;
+    read_reply(io).await
+}
+
+/// Reads a single reply from a client, retrying on timeouts.
+///
+/// See [`read_reply_with_timeouts`] for how long replies are read.
+///
+/// [`read_reply_with_timeouts`]: ../fastboot/fn.read_reply_with_timeouts.html
+pub async fn read_reply<T: AsyncFastboot>(io: &mut T) -> FbResult<Reply> {
+    let mut reply = Vec::with_capacity(FB_MAX_REPLY_LEN);
+    let mut received = read_reply_part(io, &mut reply, DEFAULT_MAX_TIMEOUTS).await?;
+    while received == FB_MAX_REPLY_LEN && reply.len() < FB_MAX_ASSEMBLED_REPLY_LEN {
+        received = match read_reply_part(io, &mut reply, 1).await {
+            Ok(received) => received,
+            Err(FastbootError::Timeout) => break,
+            Err(err) => return Err(err),
+        };
+    }
+    trace("<-", &reply);
+    Ok(Reply::from(&mut reply[..]))
+}
+
+// Appends a single read to `reply`, returning its length
+async fn read_reply_part<T: AsyncFastboot>(
+    io: &mut T,
+    reply: &mut Vec<u8>,
+    max_timeouts: usize,
+) -> FbResult<usize> {
+    let mut timeouts = 0;
+    loop {
+        let mut buff = [0; FB_MAX_REPLY_LEN];
+        match io.read(&mut buff).await {
+            Ok(received) => {
+                reply.extend_from_slice(&buff[..received]);
+                return Ok(received);
+            }
+            Err(err) => match err.kind() {
+                std::io::ErrorKind::TimedOut => {
+                    timeouts += 1;
+                    if timeouts >= max_timeouts {
+                        return Err(FastbootError::Timeout);
+                    }
+                }
+                _ => return Err(FastbootError::from(err)),
+            },
+        }
+    }
+}
+
+/// Reads replies until a final one, passing every `INFO`/`TEXT` line to `on_info`.
+///
+/// See [`read_final_reply`] for details.
+///
+/// [`read_final_reply`]: ../fastboot/fn.read_final_reply.html
+pub async fn read_final_reply<T: AsyncFastboot>(
+    io: &mut T,
+    on_info: &mut (dyn FnMut(&str) + Send),
+) -> FbResult<Reply> {
+    loop {
+        match read_reply(io).await? {
+            Reply::INFO(line) | Reply::TEXT(line) => on_info(&line),
+            reply => return Ok(reply),
+        }
+    }
+}
+
+/// Sends a raw command to a client and waits for its final reply.
+///
+/// See [`send_command_with_info`] for details.
+///
+/// [`send_command_with_info`]: ../fastboot/fn.send_command_with_info.html
+pub async fn send_command_with_info<T: AsyncFastboot>(
+    io: &mut T,
+    payload: &[u8],
+    on_info: &mut (dyn FnMut(&str) + Send),
+) -> FbResult<Reply> {
+    trace("->", payload);
+    io.write_all(payload).await?;
+    read_final_reply(io, on_info).await
+}
+
+/// The `AsyncFastboot` trait provides asynchronous Fastboot-protocol host-side interface.
+///
+/// Like [`Fastboot`], it's implemented for everything that implements
+/// [`AsyncRead`], [`AsyncWrite`], [`Unpin`] and [`Send`]. Futures returned
+/// by its methods are `Send` too, so they can be spawned on a multi-threaded
+/// runtime.
+///
+/// [`Fastboot`]: ../fastboot/trait.Fastboot.html
+pub trait AsyncFastboot: AsyncRead + AsyncWrite + Unpin + Send + Sized {
+    /// Gets a Fastboot variable.
+    fn getvar(&mut self, var: &str) -> impl Future<Output = FbResult<String>> + Send {
+        async move {
+            let mut cmd = Vec::with_capacity(GETVAR_CMD.len() + var.len());
+            cmd.extend_from_slice(GETVAR_CMD);
+            cmd.extend_from_slice(var.as_bytes());
+            let reply = send_command_with_info(self, &cmd, &mut |_| {}).await?;
+            match reply {
+                Reply::OKAY(variable) => Ok(variable),
+                Reply::FAIL(message) => Err(FastbootError::Fail(message)),
+                _ => Err(FastbootError::Protocol("Unexpected reply".to_owned())),
+            }
+        }
+    }
+
+    /// Downloads provided data into a client.
+    ///
+    /// Data that exceeds the client's `max-download-size` is refused without
+    /// being sent. Clients that don't report the limit aren't checked.
+    fn download(&mut self, data: &[u8]) -> impl Future<Output = FbResult<()>> + Send {
+        async move {
+            let limit = self
+                .getvar(MAX_DOWNLOAD_SIZE_VAR)
+                .await
+                .and_then(|limit| parse_var_number(MAX_DOWNLOAD_SIZE_VAR, &limit))
+                .map(|limit| limit as usize);
+            check_download_size(known_limit(limit)?, data.len() as u64)?;
+
+            let mut cmd = [0; DOWNLOAD_COMMAND_LEN];
+            let len = protocol::encode_download(&mut cmd, data.len() as u64).ok_or_else(|| {
+                FastbootError::Protocol(format!("Download of {} bytes is too big", data.len()))
+            })?;
+            let reply = send_command_with_info(self, &cmd[..len], &mut |_| {}).await?;
+            match reply {
+                Reply::DATA(size) if size == data.len() => {
+                    for chunk in data.chunks(DOWNLOAD_CHUNK_LEN) {
+                        self.write_all(chunk).await?;
+                    }
+                    self.flush().await?;
+                    let reply = read_final_reply(self, &mut |_| {}).await?;
+                    match reply {
+                        Reply::OKAY(_) => Ok(()),
+                        Reply::FAIL(message) => Err(FastbootError::Fail(message)),
+                        _ => Err(FastbootError::Protocol("Unexpected reply".to_owned())),
+                    }
+                }
+                Reply::DATA(size) => Err(FastbootError::Protocol(format!(
+                    "Device accepted {} bytes but {} were requested",
+                    size,
+                    data.len()
+                ))),
+                Reply::FAIL(message) => Err(FastbootError::Fail(message)),
+                _ => Err(FastbootError::Protocol("Unexpected reply".to_owned())),
+            }
+        }
+    }
+
+    /// Flashes downloaded data into a specified partition.
+    fn flash(&mut self, partition: &str) -> impl Future<Output = FbResult<()>> + Send {
+        async move {
+            check_partition(partition)?;
+            let mut cmd = Vec::with_capacity(FLASH_CMD.len() + partition.len());
+            cmd.extend_from_slice(FLASH_CMD);
+            cmd.extend_from_slice(partition.as_bytes());
+            let reply = send_command_with_info(self, &cmd, &mut |_| {}).await?;
+            match reply {
+                Reply::OKAY(_) => Ok(()),
+                Reply::FAIL(message) => Err(FastbootError::Fail(message)),
+                _ => Err(FastbootError::Protocol("Unexpected reply".to_owned())),
+            }
+        }
+    }
+
+    /// Erases a specified partition.
+    fn erase(&mut self, partition: &str) -> impl Future<Output = FbResult<()>> + Send {
+        async move {
+            check_partition(partition)?;
+            let mut cmd = Vec::with_capacity(ERASE_CMD.len() + partition.len());
+            cmd.extend_from_slice(ERASE_CMD);
+            cmd.extend_from_slice(partition.as_bytes());
+            let reply = send_command_with_info(self, &cmd, &mut |_| {}).await?;
+            match reply {
+                Reply::OKAY(_) => Ok(()),
+                Reply::FAIL(message) => Err(FastbootError::Fail(message)),
+                _ => Err(FastbootError::Protocol("Unexpected reply".to_owned())),
+            }
+        }
+    }
+
+    /// Reboots a client.
+    fn reboot(&mut self) -> impl Future<Output = FbResult<()>> + Send {
+        async move {
+            let reply = send_command_with_info(self, REBOOT_CMD, &mut |_| {}).await?;
+            match reply {
+                Reply::OKAY(_) => Ok(()),
+                Reply::FAIL(message) => Err(FastbootError::Fail(message)),
+                _ => Err(FastbootError::Protocol("Unexpected reply".to_owned())),
+            }
+        }
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Sized> AsyncFastboot for T {}
@@ -1,67 +1,461 @@
 //! Traits, helpers, and type definitions for Fastboot host functionality.
 
 use std;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
 use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
 
-///! Result wrapper that yields either a succesful result of a Fastboot operation
-///! or an error [`String`].
-pub type FbResult<T> = Result<T, String>;
+use crate::protocol::{self, DecodeError, RawReply};
+use crate::sparse::{self, SparseImage};
+#[cfg(feature = "zip")]
+use crate::update::UpdatePackage;
 
-const GETVAR_CMD: &[u8] = b"getvar:";
-const DOWNLOAD_CMD: &[u8] = b"download:";
-const FLASH_CMD: &[u8] = b"flash:";
-const ERASE_CMD: &[u8] = b"erase:";
-const REBOOT_CMD: &[u8] = b"reboot";
+/// Result wrapper that yields either a successful result of a Fastboot operation
+/// or a [`FastbootError`].
+pub type FbResult<T> = Result<T, FastbootError>;
 
-enum Reply {
+/// Errors that can happen during a Fastboot operation.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FastbootError {
+    /// A client replied with `FAIL` and the enclosed message.
+    Fail(String),
+    /// Underlying I/O implementation failed.
+    Io(String),
+    /// A client replied with something that doesn't fit the protocol.
+    Protocol(String),
+    /// Underlying I/O implementation timed out.
+    Timeout,
+    /// The operation was cancelled, see [`FastbootSession::set_cancel_flag`].
+    ///
+    /// [`FastbootSession::set_cancel_flag`]: ../session/struct.FastbootSession.html#method.set_cancel_flag
+    Cancelled,
+    /// A client went away, e.g. it reset in the middle of a command.
+    ///
+    /// Transports report it as [`ErrorKind::NotConnected`].
+    ///
+    /// [`ErrorKind::NotConnected`]: https://doc.rust-lang.org/std/io/enum.ErrorKind.html#variant.NotConnected
+    DeviceDisconnected,
+}
+
+impl FastbootError {
+    /// Checks whether a client refused a command it doesn't know, e.g.
+    /// `FAIL unknown command`, so callers can fall back to something else.
+    pub fn is_unknown_command(&self) -> bool {
+        self.fail_reason() == Some(FailReason::UnknownCommand)
+    }
+
+    /// Classifies the message of a [`Fail`], see [`FailReason::classify`].
+    ///
+    /// [`Fail`]: #variant.Fail
+    /// [`FailReason::classify`]: enum.FailReason.html#method.classify
+    pub fn fail_reason(&self) -> Option<FailReason> {
+        match self {
+            FastbootError::Fail(message) => Some(FailReason::classify(message)),
+            _ => None,
+        }
+    }
+}
+
+/// Common reasons for a client to reply with `FAIL`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailReason {
+    /// The command isn't supported.
+    UnknownCommand,
+    /// The command isn't allowed until the bootloader is unlocked.
+    DeviceLocked,
+    /// The command isn't allowed for another reason.
+    PermissionDenied,
+    /// The partition doesn't exist.
+    PartitionNotFound,
+    /// Anything else.
+    Other,
+}
+
+impl FailReason {
+    /// Classifies a `FAIL` message by the wording clients commonly use.
+    ///
+    /// Messages aren't standardized, so this is a best-effort guess.
+    pub fn classify(message: &str) -> Self {
+        let message = message.to_lowercase();
+        let has = |patterns: &[&str]| patterns.iter().any(|pattern| message.contains(pattern));
+        if has(&[
+            "unknown command",
+            "unrecognized command",
+            "command not supported",
+        ]) {
+            FailReason::UnknownCommand
+        } else if has(&[
+            "locked state",
+            "lock state",
+            "device is locked",
+            "bootloader is locked",
+        ]) {
+            FailReason::DeviceLocked
+        } else if has(&["permission denied", "not allowed"]) {
+            FailReason::PermissionDenied
+        } else if has(&[
+            "partition not found",
+            "no such partition",
+            "unknown partition",
+            "partition does not exist",
+        ]) {
+            FailReason::PartitionNotFound
+        } else {
+            FailReason::Other
+        }
+    }
+}
+
+impl Error for FastbootError {}
+
+impl fmt::Display for FastbootError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FastbootError::Fail(message) => write!(f, "Failed: {}", message),
+            FastbootError::Io(message) => write!(f, "I/O error: {}", message),
+            FastbootError::Protocol(message) => write!(f, "Protocol error: {}", message),
+            FastbootError::Timeout => write!(f, "Timed out"),
+            FastbootError::Cancelled => write!(f, "Cancelled"),
+            FastbootError::DeviceDisconnected => write!(f, "Device disconnected"),
+        }
+    }
+}
+
+impl From<std::io::Error> for FastbootError {
+    fn from(err: std::io::Error) -> Self {
+        // Transports wrap errors of their own, e.g. a cancellation, into I/O ones
+        if let Some(err) = err
+            .get_ref()
+            .and_then(|err| err.downcast_ref::<FastbootError>())
+        {
+            return err.clone();
+        }
+        match err.kind() {
+            std::io::ErrorKind::TimedOut => FastbootError::Timeout,
+            std::io::ErrorKind::NotConnected => FastbootError::DeviceDisconnected,
+            _ => FastbootError::Io(err.to_string()),
+        }
+    }
+}
+
+pub(crate) const GETVAR_CMD: &[u8] = b"getvar:";
+const GETVAR_ALL_CMD: &[u8] = b"getvar:all";
+pub(crate) const MAX_DOWNLOAD_SIZE_VAR: &str = "max-download-size";
+pub(crate) const CURRENT_SLOT_VAR: &str = "current-slot";
+const VERSION_VAR: &str = "version";
+const IS_USERSPACE_VAR: &str = "is-userspace";
+// Userspace Fastboot came with this version of the protocol
+const USERSPACE_VERSION: (u16, u16) = (0, 4);
+const UNLOCKED_VAR: &str = "unlocked";
+const SLOT_COUNT_VAR: &str = "slot-count";
+const HAS_SLOT_VAR: &str = "has-slot:";
+const IS_LOGICAL_VAR: &str = "is-logical:";
+pub(crate) const DOWNLOAD_CMD: &[u8] = b"download:";
+pub(crate) const FLASH_CMD: &[u8] = b"flash:";
+pub(crate) const ERASE_CMD: &[u8] = b"erase:";
+pub(crate) const REBOOT_CMD: &[u8] = b"reboot";
+const OEM_CMD: &[u8] = b"oem ";
+const SET_ACTIVE_CMD: &[u8] = b"set_active:";
+const REBOOT_BOOTLOADER_CMD: &[u8] = b"reboot-bootloader";
+const REBOOT_RECOVERY_CMD: &[u8] = b"reboot-recovery";
+const REBOOT_FASTBOOT_CMD: &[u8] = b"reboot-fastboot";
+const BOOT_CMD: &[u8] = b"boot";
+const CONTINUE_CMD: &[u8] = b"continue";
+const UPLOAD_CMD: &[u8] = b"upload";
+const FETCH_CMD: &[u8] = b"fetch:";
+const CREATE_LOGICAL_PARTITION_CMD: &[u8] = b"create-logical-partition:";
+const DELETE_LOGICAL_PARTITION_CMD: &[u8] = b"delete-logical-partition:";
+const RESIZE_LOGICAL_PARTITION_CMD: &[u8] = b"resize-logical-partition:";
+const PARTITION_TYPE_VAR: &str = "partition-type:";
+const PARTITION_SIZE_VAR: &str = "partition-size:";
+const FLASHING_UNLOCK_CMD: &[u8] = b"flashing unlock";
+const FLASHING_LOCK_CMD: &[u8] = b"flashing lock";
+const FLASHING_GET_UNLOCK_ABILITY_CMD: &[u8] = b"flashing get_unlock_ability";
+const POWERDOWN_CMD: &[u8] = b"powerdown";
+const SIGNATURE_CMD: &[u8] = b"signature";
+const VERIFY_CMD: &[u8] = b"verify:";
+pub(crate) const IMAGE_SUFFIX: &str = ".img";
+// Variables that take an argument, which `getvar all` reports as part of the name
+const ARGUMENT_VARS: &[&str] = &[
+    PARTITION_SIZE_VAR,
+    PARTITION_TYPE_VAR,
+    HAS_SLOT_VAR,
+    IS_LOGICAL_VAR,
+];
+// Images flashed before all others, each followed by a reboot into the new bootloader
+pub(crate) const BOOTLOADER_IMAGES: &[&str] = &["bootloader", "radio"];
+// Images of a build output directory flashed by `flash_dir`, besides the
+// bootloader ones. Like `fastboot flashall`, user data isn't wiped.
+const DIR_IMAGES: &[&str] = &[
+    "boot",
+    "dt",
+    "dtbo",
+    "init_boot",
+    "odm",
+    "odm_dlkm",
+    "product",
+    "pvmfw",
+    "recovery",
+    "super",
+    "system",
+    "system_dlkm",
+    "system_ext",
+    "vbmeta",
+    "vbmeta_system",
+    "vbmeta_vendor",
+    "vendor",
+    "vendor_boot",
+    "vendor_dlkm",
+    "vendor_kernel_boot",
+];
+
+/// A reply sent by a client, split at its 4-byte prefix.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply {
+    /// A command succeeded, with an optional payload.
     OKAY(String),
+    /// A client is ready to send or receive this many bytes.
     DATA(usize),
+    /// A command failed with the enclosed message.
     FAIL(String),
+    /// An informational message, more replies will follow.
     INFO(String),
+    /// Human-readable output, more replies will follow.
+    TEXT(String),
+    /// Something with an unknown prefix, as received. It's most likely a sign
+    /// of the transport getting out of sync rather than a failure reported by
+    /// a client, so commands report it as [`FastbootError::Protocol`].
+    ///
+    /// [`FastbootError::Protocol`]: enum.FastbootError.html#variant.Protocol
+    Unknown(Vec<u8>),
 }
 
 impl<'s> From<&'s mut [u8]> for Reply {
     fn from(reply: &'s mut [u8]) -> Self {
-        // Split a reply at OKAY/FAIL/DATA
-        let (first, second) = reply.split_at(4);
-        let second = String::from_utf8_lossy(second);
-        match first {
-            b"OKAY" => Reply::OKAY(second.into_owned()),
-            b"INFO" => Reply::INFO(second.into_owned()),
-            b"FAIL" => Reply::FAIL(second.into_owned()),
-            b"DATA" => match usize::from_str_radix(&second, 16) {
-                Ok(size) => Reply::DATA(size),
-                _ => Reply::FAIL("Failed to decode DATA size".to_owned()),
-            },
-            _ => {
-                eprintln!("Received: {}", second);
-                Reply::FAIL(second.into_owned())
+        // Only the payload may not be valid UTF-8
+        let lossy = |bytes: &[u8]| String::from_utf8_lossy(bytes).into_owned();
+        match protocol::decode_reply(reply) {
+            Ok(RawReply::Okay(payload)) => Reply::OKAY(lossy(payload)),
+            Ok(RawReply::Info(payload)) => Reply::INFO(lossy(payload)),
+            Ok(RawReply::Text(payload)) => Reply::TEXT(lossy(payload)),
+            Ok(RawReply::Fail(payload)) => Reply::FAIL(lossy(payload)),
+            Ok(RawReply::Data(size)) => Reply::DATA(size),
+            Err(DecodeError::Truncated) => {
+                Reply::FAIL(format!("Truncated reply: {}", lossy(reply)))
             }
+            Err(DecodeError::InvalidDataSize) => {
+                Reply::FAIL("Failed to decode DATA size".to_owned())
+            }
+            Err(DecodeError::UnknownPrefix) => Reply::Unknown(reply.to_vec()),
+        }
+    }
+}
+
+// Replies are received by reads of this size. The protocol limits them to
+// 64 bytes, but some clients send longer ones, which fit into a single packet
+// of high-speed USB bulk endpoints. Transports that read no more than their
+// own packet size are expected to keep reading while packets are full, so
+// the buffer is sized for the biggest one. Bigger packets, e.g. SuperSpeed
+// ones, are read by sessions from `FastbootSession::new_usb()`.
+pub(crate) const FB_MAX_REPLY_LEN: usize = 512;
+// Replies that fill a whole read go on in the next one, up to this length
+pub(crate) const FB_MAX_ASSEMBLED_REPLY_LEN: usize = 8 * FB_MAX_REPLY_LEN;
+// How much data is written at once during a download
+pub(crate) const DOWNLOAD_CHUNK_LEN: usize = 1024 * 1024;
+
+/// Everything a client reported in response to a successful command.
+///
+/// It's displayed as `INFO`/`TEXT` lines followed by the `OKAY` payload (if
+/// any), one per line, much like the `fastboot` tool prints them.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct CommandOutcome {
+    /// `INFO` and `TEXT` lines sent before the final reply.
+    pub info: Vec<String>,
+    /// Payload of the final `OKAY` reply.
+    pub okay_payload: String,
+}
+
+impl fmt::Display for CommandOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let payload = Some(self.okay_payload.as_str()).filter(|payload| !payload.is_empty());
+        let lines: Vec<&str> = self
+            .info
+            .iter()
+            .map(String::as_str)
+            .chain(payload)
+            .collect();
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+/// How many consecutive timeouts [`send_command`] and [`read_reply`] tolerate
+/// before giving up with [`FastbootError::Timeout`].
+///
+/// [`send_command`]: fn.send_command.html
+/// [`read_reply`]: fn.read_reply.html
+/// [`FastbootError::Timeout`]: enum.FastbootError.html#variant.Timeout
+pub const DEFAULT_MAX_TIMEOUTS: usize = 30;
+
+// How many stale replies getting back in sync may discard
+const MAX_DRAIN_READS: usize = 16;
+
+// Logs a command sent or a reply received, see the `log` feature.
+#[cfg(feature = "log")]
+pub(crate) fn trace(direction: &str, bytes: &[u8]) {
+    log::debug!("{} {:?}", direction, String::from_utf8_lossy(bytes));
+}
+
+#[cfg(not(feature = "log"))]
+pub(crate) fn trace(_: &str, _: &[u8]) {}
+
+/// Sends a raw command to a client and waits for its first reply.
+///
+/// According to U-Boot documentation, Fastboot is a synchronous protocol. Therefore
+/// we should always wait for a reply to our "request". This function will block until
+/// a reply or an error is received from USB I/O implementation, or until
+/// [`DEFAULT_MAX_TIMEOUTS`] timeouts happen in a row.
+/// See u-boot/doc/README.android-fastboot-protocol
+///
+/// Use [`read_reply`] to receive any further replies, e.g. after `INFO`.
+///
+/// [`DEFAULT_MAX_TIMEOUTS`]: constant.DEFAULT_MAX_TIMEOUTS.html
+/// [`read_reply`]: fn.read_reply.html
+pub fn send_command<T: Fastboot>(io: &mut T, payload: &[u8]) -> FbResult<Reply> {
+    send_command_with_timeouts(io, payload, DEFAULT_MAX_TIMEOUTS)
+}
+
+/// Same as [`send_command`], but gives up after `max_timeouts` timeouts in a row.
+///
+/// [`send_command`]: fn.send_command.html
+pub fn send_command_with_timeouts<T: Fastboot>(
+    io: &mut T,
+    payload: &[u8],
+    max_timeouts: usize,
+) -> FbResult<Reply> {
+    trace("->", payload);
+    io.write_all(payload)?;
+    read_reply_with_timeouts(io, max_timeouts)
+}
+
+/// Same as [`send_command`], but recovers once from the transport getting out
+/// of sync, e.g. after something else wrote to the client.
+///
+/// If the first reply is [`Reply::Unknown`], anything left to read is
+/// discarded, which takes a timeout of the transport, and `payload` is sent
+/// again. Only use it for commands that are safe to repeat, e.g. `getvar`.
+///
+/// [`send_command`]: fn.send_command.html
+/// [`Reply::Unknown`]: enum.Reply.html#variant.Unknown
+pub fn send_command_with_resync<T: Fastboot>(io: &mut T, payload: &[u8]) -> FbResult<Reply> {
+    match send_command(io, payload)? {
+        Reply::Unknown(reply) => {
+            warn_resync(payload, &reply);
+            drain(io)?;
+            send_command(io, payload)
+        }
+        reply => Ok(reply),
+    }
+}
+
+// Reads until a read times out, giving up if replies keep coming
+fn drain<T: Fastboot>(io: &mut T) -> FbResult<()> {
+    for _ in 0..MAX_DRAIN_READS {
+        match read_reply_with_timeouts(io, 1) {
+            Ok(_) => (),
+            Err(FastbootError::Timeout) => return Ok(()),
+            Err(err) => return Err(err),
         }
     }
+    Err(FastbootError::Protocol(
+        "Failed to get back in sync".to_owned(),
+    ))
 }
 
-const FB_MAX_REPLY_LEN: usize = 64;
-// According to U-Boot documentation, Fastboot is a synchronous protocol. Therefore
-// we should always wait for a reply to our "request". This function will block until
-// a reply or an error (except timeout) is received from USB I/O implementation.
-// See u-boot/doc/README.android-fastboot-protocol
-fn fb_send<T: Fastboot>(io: &mut T, payload: &[u8]) -> FbResult<Reply> {
-    io.write_all(payload).map_err(|err| err.to_string())?;
+#[cfg(feature = "log")]
+fn warn_resync(payload: &[u8], reply: &[u8]) {
+    log::warn!(
+        "Out of sync after {:?}, got {:?}, sending it again",
+        String::from_utf8_lossy(payload),
+        String::from_utf8_lossy(reply)
+    );
+}
+
+#[cfg(not(feature = "log"))]
+fn warn_resync(_: &[u8], _: &[u8]) {}
+
+/// Reads a single reply from a client, retrying on timeouts.
+pub fn read_reply<T: Fastboot>(io: &mut T) -> FbResult<Reply> {
+    read_reply_with_timeouts(io, DEFAULT_MAX_TIMEOUTS)
+}
+
+/// Same as [`read_reply`], but gives up after `max_timeouts` timeouts in a row.
+///
+/// A reply that fills a whole read is assumed to go on in the next one, which
+/// may time out once if it doesn't.
+///
+/// [`read_reply`]: fn.read_reply.html
+pub fn read_reply_with_timeouts<T: Fastboot>(io: &mut T, max_timeouts: usize) -> FbResult<Reply> {
+    let mut reply = read_raw_reply(io, max_timeouts)?;
+    Ok(Reply::from(&mut reply[..]))
+}
+
+// Reads a single reply as is, see `read_reply_with_timeouts()`
+fn read_raw_reply<T: Fastboot>(io: &mut T, max_timeouts: usize) -> FbResult<Vec<u8>> {
+    let mut reply = Vec::with_capacity(FB_MAX_REPLY_LEN);
+    let mut received = read_reply_part(io, &mut reply, max_timeouts)?;
+    while received == FB_MAX_REPLY_LEN && reply.len() < FB_MAX_ASSEMBLED_REPLY_LEN {
+        received = match read_reply_part(io, &mut reply, 1) {
+            Ok(received) => received,
+            // Nothing more was sent
+            Err(FastbootError::Timeout) => break,
+            Err(err) => return Err(err),
+        };
+    }
+    trace("<-", &reply);
+    Ok(reply)
+}
+
+// Sends a command and reads its first reply as is
+fn send_raw_command<T: Fastboot>(io: &mut T, payload: &[u8]) -> FbResult<Vec<u8>> {
+    trace("->", payload);
+    io.write_all(payload)?;
+    read_raw_reply(io, DEFAULT_MAX_TIMEOUTS)
+}
+
+// Appends a single read to `reply`, returning its length
+fn read_reply_part<T: Fastboot>(
+    io: &mut T,
+    reply: &mut Vec<u8>,
+    max_timeouts: usize,
+) -> FbResult<usize> {
+    let mut timeouts = 0;
     loop {
         let mut buff = [0; FB_MAX_REPLY_LEN];
         match io.read(&mut buff) {
-            Ok(received) => return Ok(Reply::from(&mut buff[..received])),
+            Ok(received) => {
+                reply.extend_from_slice(&buff[..received]);
+                return Ok(received);
+            }
             Err(err) => {
                 match err.kind() {
                     std::io::ErrorKind::TimedOut => {
                         // Trait can't possible now what is a timeout set by a particular Read/Write implementation
-                        // so it will *not* consider TimedOut a fatal error. Instead it will just try again
-                        // until a reply or another error is received.
+                        // so it will *not* consider a single TimedOut a fatal error. Instead it will just try again
+                        // until a reply or another error is received, or until the device looks stuck.
+                        timeouts += 1;
+                        if timeouts >= max_timeouts {
+                            return Err(FastbootError::Timeout);
+                        }
                         continue;
                     }
                     _ => {
-                        return Err(err.to_string());
+                        return Err(FastbootError::from(err));
                     }
                 };
             }
@@ -69,85 +463,1837 @@ fn fb_send<T: Fastboot>(io: &mut T, payload: &[u8]) -> FbResult<Reply> {
     }
 }
 
-/// The `Fastboot` trait provides Fastboot-protocol host-side interface.
+/// Reads replies until a final one, i.e. anything but `INFO` or `TEXT`,
+/// passing every `INFO`/`TEXT` line to `on_info`.
+pub fn read_final_reply<T: Fastboot>(io: &mut T, on_info: &mut dyn FnMut(&str)) -> FbResult<Reply> {
+    loop {
+        match read_reply(io)? {
+            Reply::INFO(line) | Reply::TEXT(line) => on_info(&line),
+            reply => return Ok(reply),
+        }
+    }
+}
+
+/// Sends a raw command to a client and waits for its final reply.
 ///
-/// There are no required methods. The only requirement is that an object,
-/// implementing this trait implements also [`Read`], [`Write`] and [`Sized`] traits.
-pub trait Fastboot: Read + Write + Sized {
-    /// Gets a Fastboot variable.
-    ///
-    /// NOTE: Fastboot variables aren't U-Boot environment variables.
-    fn getvar(&mut self, var: &str) -> FbResult<String> {
-        let mut cmd = Vec::with_capacity(GETVAR_CMD.len() + var.len());
-        cmd.extend_from_slice(GETVAR_CMD);
-        cmd.extend_from_slice(var.as_bytes());
-        let reply = fb_send(self, &cmd)?;
-        match reply {
-            Reply::OKAY(variable) => Ok(variable),
-            Reply::FAIL(message) => Err(message),
-            _ => Err("Unknown failure".to_owned()),
+/// See [`send_command`] and [`read_final_reply`].
+///
+/// [`send_command`]: fn.send_command.html
+/// [`read_final_reply`]: fn.read_final_reply.html
+pub fn send_command_with_info<T: Fastboot>(
+    io: &mut T,
+    payload: &[u8],
+    on_info: &mut dyn FnMut(&str),
+) -> FbResult<Reply> {
+    trace("->", payload);
+    io.write_all(payload)?;
+    read_final_reply(io, on_info)
+}
+
+/// Sends a raw command to a client and collects everything it reports
+/// until the final reply.
+///
+/// `FAIL` is returned as [`FastbootError::Fail`].
+///
+/// [`FastbootError::Fail`]: enum.FastbootError.html#variant.Fail
+pub fn run_command<T: Fastboot>(io: &mut T, payload: &[u8]) -> FbResult<CommandOutcome> {
+    let mut info = Vec::new();
+    let reply = send_command_with_info(io, payload, &mut |line| info.push(line.to_owned()))?;
+    match reply {
+        Reply::OKAY(okay_payload) => Ok(CommandOutcome { info, okay_payload }),
+        Reply::FAIL(message) => Err(FastbootError::Fail(message)),
+        _ => Err(FastbootError::Protocol("Unexpected reply".to_owned())),
+    }
+}
+
+/// A line of output sent before the final reply, see [`Fastboot::command_lines`].
+///
+/// Payloads are kept as received, as some vendor-specific commands send ones
+/// that aren't valid UTF-8, e.g. binary dumps.
+///
+/// [`Fastboot::command_lines`]: trait.Fastboot.html#method.command_lines
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Line {
+    /// Payload of an `INFO` reply.
+    Info(Vec<u8>),
+    /// Payload of a `TEXT` reply.
+    Text(Vec<u8>),
+}
+
+impl Line {
+    /// Gets the payload as received.
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            Line::Info(payload) | Line::Text(payload) => payload,
         }
     }
 
-    /// Downloads provided data into a client.
-    fn download(&mut self, data: &[u8]) -> FbResult<()> {
-        // Wrapped in block to drop len as soon as possible
-        let cmd = {
-            let mut cmd = Vec::with_capacity(DOWNLOAD_CMD.len() + 8);
-            let mut len = format!("{:08x}", data.len()).into_bytes();
-            cmd.extend_from_slice(DOWNLOAD_CMD);
-            cmd.append(&mut len);
-            cmd
+    /// Gets the payload as text, replacing invalid UTF-8.
+    pub fn to_string_lossy(&self) -> String {
+        String::from_utf8_lossy(self.as_bytes()).into_owned()
+    }
+}
+
+/// An iterator over the output of a command, see [`Fastboot::command_lines`].
+///
+/// Dropping it before the end reads the remaining replies, so the client is
+/// ready for the next command.
+///
+/// [`Fastboot::command_lines`]: trait.Fastboot.html#method.command_lines
+pub struct CommandLines<'a, T: Fastboot> {
+    io: &'a mut T,
+    // The first reply, or why the command couldn't be sent
+    first: Option<FbResult<Vec<u8>>>,
+    done: bool,
+}
+
+impl<'a, T: Fastboot> Iterator for CommandLines<'a, T> {
+    type Item = FbResult<Line>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let reply = match self.first.take() {
+            Some(reply) => reply,
+            None => read_raw_reply(self.io, DEFAULT_MAX_TIMEOUTS),
+        };
+        let line = match reply {
+            Ok(mut reply) => match protocol::decode_reply(&reply) {
+                Ok(RawReply::Info(line)) => return Some(Ok(Line::Info(line.to_vec()))),
+                Ok(RawReply::Text(line)) => return Some(Ok(Line::Text(line.to_vec()))),
+                _ => match Reply::from(&mut reply[..]) {
+                    Reply::OKAY(_) => None,
+                    Reply::FAIL(message) => Some(Err(FastbootError::Fail(message))),
+                    _ => Some(Err(FastbootError::Protocol("Unexpected reply".to_owned()))),
+                },
+            },
+            Err(err) => Some(Err(err)),
         };
-        let reply = fb_send(self, &cmd)?;
+        self.done = true;
+        line
+    }
+}
 
-        match reply {
-            Reply::DATA(size) if size == data.len() => {
-                let reply = fb_send(self, data)?;
-                match reply {
-                    Reply::OKAY(_) => Ok(()),
-                    Reply::FAIL(message) => Err(message),
-                    _ => Err("Unknown failure".to_owned()),
-                }
+impl<'a, T: Fastboot> Drop for CommandLines<'a, T> {
+    fn drop(&mut self) {
+        while let Some(Ok(_)) = self.next() {}
+    }
+}
+
+/// Well-known Fastboot variables, see [`Fastboot::getvar_typed`].
+///
+/// Use [`Fastboot::getvar`] for anything else, e.g. vendor-specific ones.
+///
+/// [`Fastboot::getvar_typed`]: trait.Fastboot.html#method.getvar_typed
+/// [`Fastboot::getvar`]: trait.Fastboot.html#method.getvar
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Var {
+    /// Version of the protocol, `version`.
+    Version,
+    /// Version of the bootloader, `version-bootloader`.
+    VersionBootloader,
+    /// Version of the baseband, `version-baseband`.
+    VersionBaseband,
+    /// Product name, `product`.
+    Product,
+    /// Serial number, `serialno`.
+    SerialNo,
+    /// Whether images must be signed, `secure`.
+    Secure,
+    /// Whether the bootloader is unlocked, `unlocked`.
+    Unlocked,
+    /// Biggest payload accepted in a single download, `max-download-size`.
+    MaxDownloadSize,
+    /// Active slot of A/B devices, `current-slot`.
+    CurrentSlot,
+    /// Number of slots, `slot-count`.
+    SlotCount,
+    /// Whether it's userspace Fastboot (fastbootd), `is-userspace`.
+    IsUserspace,
+    /// Whether a partition has A/B slots, `has-slot:<partition>`.
+    HasSlot(String),
+    /// Filesystem type of a partition, `partition-type:<partition>`.
+    PartitionType(String),
+    /// Size of a partition in bytes, `partition-size:<partition>`.
+    PartitionSize(String),
+    /// Whether a partition is a logical one, `is-logical:<partition>`.
+    IsLogical(String),
+}
+
+impl Var {
+    /// Gets the name to query the variable by.
+    pub fn name(&self) -> String {
+        match self {
+            Var::Version => VERSION_VAR.to_owned(),
+            Var::VersionBootloader => "version-bootloader".to_owned(),
+            Var::VersionBaseband => "version-baseband".to_owned(),
+            Var::Product => "product".to_owned(),
+            Var::SerialNo => "serialno".to_owned(),
+            Var::Secure => "secure".to_owned(),
+            Var::Unlocked => UNLOCKED_VAR.to_owned(),
+            Var::MaxDownloadSize => MAX_DOWNLOAD_SIZE_VAR.to_owned(),
+            Var::CurrentSlot => CURRENT_SLOT_VAR.to_owned(),
+            Var::SlotCount => SLOT_COUNT_VAR.to_owned(),
+            Var::IsUserspace => IS_USERSPACE_VAR.to_owned(),
+            Var::HasSlot(partition) => format!("{}{}", HAS_SLOT_VAR, partition),
+            Var::PartitionType(partition) => format!("{}{}", PARTITION_TYPE_VAR, partition),
+            Var::PartitionSize(partition) => format!("{}{}", PARTITION_SIZE_VAR, partition),
+            Var::IsLogical(partition) => format!("{}{}", IS_LOGICAL_VAR, partition),
+        }
+    }
+
+    // Parses a value reported by a client into the type of the variable
+    fn parse(&self, value: String) -> FbResult<VarValue> {
+        let parsed = match self {
+            Var::MaxDownloadSize | Var::SlotCount | Var::PartitionSize(_) => {
+                parse_number(&value).map(VarValue::Number)
             }
-            Reply::FAIL(message) => Err(message),
-            _ => Err("Unknown failure".to_owned()),
+            Var::Secure
+            | Var::Unlocked
+            | Var::IsUserspace
+            | Var::HasSlot(_)
+            | Var::IsLogical(_) => parse_flag(&value).map(VarValue::Flag),
+            _ => return Ok(VarValue::Text(value)),
+        };
+        parsed
+            .ok_or_else(|| FastbootError::Protocol(format!("Invalid {}: {:?}", self.name(), value)))
+    }
+}
+
+/// A value of a [`Var`], typed after the variable.
+///
+/// [`Var`]: enum.Var.html
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VarValue {
+    /// Sizes and counts, either hex with a `0x` prefix or decimal.
+    Number(u64),
+    /// `yes` or `no`.
+    Flag(bool),
+    /// Anything else as reported.
+    Text(String),
+}
+
+/// A problem found by [`Fastboot::flash_preflight`].
+///
+/// [`Fastboot::flash_preflight`]: trait.Fastboot.html#method.flash_preflight
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PreflightIssue {
+    /// A client doesn't know the partition.
+    UnknownPartition,
+    /// The image doesn't fit into the partition, which is this many bytes.
+    TooBig(u64),
+    /// The bootloader is locked, so flashing will most likely be refused.
+    Locked,
+    /// A variable needed for a check isn't reported, so it was skipped.
+    Unchecked(String),
+}
+
+/// Outcome of [`Fastboot::flash_preflight`].
+///
+/// [`Fastboot::flash_preflight`]: trait.Fastboot.html#method.flash_preflight
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct PreflightReport {
+    /// Problems that make flashing fail or do harm.
+    pub errors: Vec<PreflightIssue>,
+    /// Checks that couldn't be done.
+    pub warnings: Vec<PreflightIssue>,
+}
+
+impl PreflightReport {
+    /// Checks whether nothing prevents flashing.
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+/// What a client supports, see [`Fastboot::probe`].
+///
+/// [`Fastboot::probe`]: trait.Fastboot.html#method.probe
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Capabilities {
+    /// Version of the protocol, see [`Fastboot::protocol_version`].
+    ///
+    /// [`Fastboot::protocol_version`]: trait.Fastboot.html#method.protocol_version
+    pub protocol_version: Option<(u16, u16)>,
+    /// Biggest payload accepted in a single download.
+    pub max_download_size: Option<u64>,
+    /// Active slot, only reported by A/B devices.
+    pub current_slot: Option<String>,
+    /// Whether it's userspace Fastboot (fastbootd), which flashes logical
+    /// partitions.
+    pub is_userspace: bool,
+}
+
+/// Size and type of a partition, see [`Fastboot::list_partitions`].
+///
+/// [`Fastboot::list_partitions`]: trait.Fastboot.html#method.list_partitions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartitionInfo {
+    /// Name of the partition, e.g. `boot_a`.
+    pub name: String,
+    /// Size in bytes, if reported.
+    pub size: Option<u64>,
+    /// Filesystem type, e.g. `ext4` or `raw`, if reported.
+    pub partition_type: Option<String>,
+}
+
+/// How long a transfer took, e.g. to spot a slow USB link.
+///
+/// See [`Fastboot::download_stream`] and [`Fastboot::flash_file`].
+///
+/// [`Fastboot::download_stream`]: trait.Fastboot.html#method.download_stream
+/// [`Fastboot::flash_file`]: trait.Fastboot.html#method.flash_file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransferStats {
+    /// Size of the data that was transferred.
+    pub bytes: u64,
+    /// Wall-clock time from sending the first command to the final reply.
+    pub elapsed: Duration,
+}
+
+impl TransferStats {
+    /// Gets the average throughput in bytes per second, 0 if no time elapsed.
+    pub fn throughput(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            self.bytes as f64 / secs
+        } else {
+            0.0
         }
     }
+}
 
-    /// Flashes downloaded data into a specified partition.
-    fn flash(&mut self, partition: &str) -> FbResult<()> {
-        let mut cmd = Vec::with_capacity(FLASH_CMD.len() + partition.len());
-        cmd.extend_from_slice(FLASH_CMD);
-        cmd.extend_from_slice(partition.as_bytes());
-        let reply = fb_send(self, &cmd)?;
-        match reply {
-            Reply::OKAY(_) => Ok(()),
-            Reply::FAIL(message) => Err(message),
-            _ => Err("Unknown failure".to_owned()),
+/// A single step of flashing several images, see [`plan_flash_dir`].
+///
+/// It's displayed as the matching `fastboot` invocation, e.g. `flash boot boot.img`.
+///
+/// [`plan_flash_dir`]: fn.plan_flash_dir.html
+#[derive(Debug, Clone, PartialEq)]
+pub enum FlashStep {
+    /// Flash the image at `image` into `partition`.
+    Flash { partition: String, image: PathBuf },
+    /// Reboot into the bootloader that has just been flashed.
+    RebootBootloader,
+}
+
+impl fmt::Display for FlashStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FlashStep::Flash { partition, image } => {
+                write!(f, "flash {} {}", partition, image.display())
+            }
+            FlashStep::RebootBootloader => write!(f, "reboot-bootloader"),
         }
     }
+}
 
-    /// Erases a specified partition.
-    fn erase(&mut self, partition: &str) -> FbResult<()> {
-        let mut cmd = Vec::with_capacity(ERASE_CMD.len() + partition.len());
-        cmd.extend_from_slice(ERASE_CMD);
-        cmd.extend_from_slice(partition.as_bytes());
-        let reply = fb_send(self, &cmd)?;
-        match reply {
-            Reply::OKAY(_) => Ok(()),
-            Reply::FAIL(message) => Err(message),
-            _ => Err("Unknown failure".to_owned()),
+/// A command of a sequence, see [`Fastboot::run_sequence`].
+///
+/// [`Fastboot::run_sequence`]: trait.Fastboot.html#method.run_sequence
+#[derive(Debug, Clone, PartialEq)]
+pub enum Step {
+    /// Erase a partition, see [`Fastboot::erase`].
+    ///
+    /// [`Fastboot::erase`]: trait.Fastboot.html#method.erase
+    Erase(String),
+    /// Download data, see [`Fastboot::download`].
+    ///
+    /// [`Fastboot::download`]: trait.Fastboot.html#method.download
+    Download(Vec<u8>),
+    /// Flash downloaded data into a partition, see [`Fastboot::flash`].
+    ///
+    /// [`Fastboot::flash`]: trait.Fastboot.html#method.flash
+    Flash(String),
+    /// Set the active slot, see [`Fastboot::set_active`].
+    ///
+    /// [`Fastboot::set_active`]: trait.Fastboot.html#method.set_active
+    SetActive(String),
+    /// Run an OEM command, see [`Fastboot::oem`].
+    ///
+    /// [`Fastboot::oem`]: trait.Fastboot.html#method.oem
+    Oem(String),
+    /// Reboot into the bootloader.
+    RebootBootloader,
+    /// Reboot, which ends a sequence as far as the client is concerned.
+    Reboot,
+}
+
+/// Outcome of a [`Step`].
+///
+/// [`Step`]: enum.Step.html
+#[derive(Debug, Clone, PartialEq)]
+pub enum StepResult {
+    /// The step succeeded.
+    Done,
+    /// A client failed the step, or replied with something unexpected.
+    Failed(FastbootError),
+    /// The step wasn't run, as an earlier one failed.
+    Skipped,
+}
+
+/// Plans flashing of a build output directory, i.e. `<partition>.img` files,
+/// without touching a client.
+///
+/// Bootloader and radio images go first, each followed by a reboot into the
+/// new bootloader, then the rest in alphabetical order. Files that aren't
+/// images of well-known partitions, including `userdata.img`, are skipped.
+pub fn plan_flash_dir(dir: &Path) -> FbResult<Vec<FlashStep>> {
+    let mut partitions = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let partition = match path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_suffix(IMAGE_SUFFIX))
+        {
+            Some(partition)
+                if DIR_IMAGES.contains(&partition) || BOOTLOADER_IMAGES.contains(&partition) =>
+            {
+                partition.to_owned()
+            }
+            _ => continue,
+        };
+        if path.is_file() {
+            partitions.push(partition);
         }
     }
+    sort_for_flashing(&mut partitions);
 
-    /// Reboots a client.
-    fn reboot(&mut self) -> FbResult<()> {
-        let reply = fb_send(self, REBOOT_CMD)?;
+    let mut steps = Vec::new();
+    for partition in partitions {
+        let reboot = BOOTLOADER_IMAGES.contains(&partition.as_str());
+        let image = dir.join(format!("{}{}", partition, IMAGE_SUFFIX));
+        steps.push(FlashStep::Flash { partition, image });
+        if reboot {
+            steps.push(FlashStep::RebootBootloader);
+        }
+    }
+    Ok(steps)
+}
+
+// Sorts partitions in the order they are flashed: bootloader and radio first,
+// then the rest in alphabetical order.
+pub(crate) fn sort_for_flashing(partitions: &mut [String]) {
+    partitions.sort_by_key(|partition| {
+        let rank = BOOTLOADER_IMAGES
+            .iter()
+            .position(|image| image == partition)
+            .unwrap_or(BOOTLOADER_IMAGES.len());
+        (rank, partition.clone())
+    });
+}
+
+// Splits a `partition-size:<name>` or `partition-type:<name>` variable into
+// the matching prefix and the name. Some clients spell the prefix with an
+// underscore or put spaces around the name
+fn split_partition_var(name: &str) -> Option<(&'static str, &str)> {
+    let at = name.find(':')?;
+    let prefix = format!("{}:", name[..at].trim().replace('_', "-"));
+    let partition = name[at + 1..].trim();
+    [PARTITION_SIZE_VAR, PARTITION_TYPE_VAR]
+        .iter()
+        .find(|&&var| var == prefix)
+        .filter(|_| !partition.is_empty())
+        .map(|&var| (var, partition))
+}
+
+// Splits a `getvar all` line into a name and a value at the first `:`, so
+// values may contain `:` themselves. Names of variables that take an
+// argument go on to the next one, e.g. `partition-size:boot: 0x1000`
+fn split_var_line(line: &str) -> (&str, &str) {
+    let at = match line.find(':') {
+        Some(at) => at,
+        None => return (line, ""),
+    };
+    let name = format!("{}:", line[..at].trim().replace('_', "-"));
+    let at = match line[at + 1..].find(':') {
+        Some(next) if ARGUMENT_VARS.contains(&name.as_str()) => at + 1 + next,
+        _ => at,
+    };
+    (&line[..at], &line[at + 1..])
+}
+
+// Parses `version`, where a missing minor version means 0
+fn parse_version(version: &str) -> FbResult<(u16, u16)> {
+    let mut parts = version.trim().splitn(2, '.');
+    let major = parts.next().and_then(|major| major.parse().ok());
+    let minor = match parts.next() {
+        Some(minor) => minor.parse().ok(),
+        None => Some(0),
+    };
+    match (major, minor) {
+        (Some(major), Some(minor)) => Ok((major, minor)),
+        _ => Err(FastbootError::Protocol(format!(
+            "Invalid {}: {:?}",
+            VERSION_VAR, version
+        ))),
+    }
+}
+
+// Gets a variable, giving `None` if a client fails to report it or reports
+// nothing
+fn getvar_if_known<T: Fastboot>(io: &mut T, var: &str) -> FbResult<Option<String>> {
+    match io.getvar_opt(var) {
+        Err(FastbootError::Fail(_)) => Ok(None),
+        value => value,
+    }
+}
+
+fn parse_flag(value: &str) -> Option<bool> {
+    match value.trim() {
+        "yes" => Some(true),
+        "no" => Some(false),
+        _ => None,
+    }
+}
+
+// Gets a `yes`/`no` variable
+fn getvar_flag<T: Fastboot>(io: &mut T, var: &str) -> FbResult<bool> {
+    let value = io.getvar(var)?;
+    parse_flag(&value)
+        .ok_or_else(|| FastbootError::Protocol(format!("Invalid {}: {:?}", var, value)))
+}
+
+// Parses a number reported by a client, either hex with a `0x` prefix or decimal.
+pub(crate) fn parse_number(value: &str) -> Option<u64> {
+    let value = value.trim();
+    if value.starts_with("0x") || value.starts_with("0X") {
+        u64::from_str_radix(&value[2..], 16).ok()
+    } else {
+        value.parse().ok()
+    }
+}
+
+// Parses `value` of a numeric variable `var`, see `parse_number()`
+pub(crate) fn parse_var_number(var: &str, value: &str) -> FbResult<u64> {
+    parse_number(value)
+        .ok_or_else(|| FastbootError::Protocol(format!("Invalid {}: {:?}", var, value)))
+}
+
+// Reads exactly `buf.len()` bytes of a data phase. A single read is limited by
+// the USB I/O implementation (e.g. to a max packet size), so keep reading until
+// the whole buffer is filled, retrying on timeouts like `read_reply` does.
+fn fb_recv_data<T: Fastboot>(io: &mut T, buf: &mut [u8]) -> FbResult<()> {
+    let mut received = 0;
+    let mut timeouts = 0;
+    while received < buf.len() {
+        match io.read(&mut buf[received..]) {
+            Ok(0) => return Err(FastbootError::Io("Unexpected end of data".to_owned())),
+            Ok(size) => {
+                received += size;
+                timeouts = 0;
+            }
+            Err(err) => match err.kind() {
+                std::io::ErrorKind::TimedOut => {
+                    timeouts += 1;
+                    if timeouts >= DEFAULT_MAX_TIMEOUTS {
+                        return Err(FastbootError::Timeout);
+                    }
+                }
+                std::io::ErrorKind::Interrupted => continue,
+                _ => return Err(FastbootError::from(err)),
+            },
+        }
+    }
+    Ok(())
+}
+
+// Sends a command that makes a client upload data to us, e.g. `upload` or `fetch`,
+// and receives that data.
+fn fb_upload<T: Fastboot>(io: &mut T, cmd: &[u8]) -> FbResult<Vec<u8>> {
+    let mut data = Vec::new();
+    fb_upload_to(io, cmd, &mut data)?;
+    Ok(data)
+}
+
+// Same as `fb_upload`, but writes the data to `dest` as it's received, returning
+// its size.
+fn fb_upload_to<T: Fastboot, W: Write>(io: &mut T, cmd: &[u8], mut dest: W) -> FbResult<u64> {
+    let reply = send_command_with_info(io, cmd, &mut |_| {})?;
+    match reply {
+        Reply::DATA(size) => {
+            let mut chunk = vec![0; std::cmp::min(size, DOWNLOAD_CHUNK_LEN)];
+            let mut remaining = size;
+            while remaining > 0 {
+                let len = std::cmp::min(remaining, chunk.len());
+                fb_recv_data(io, &mut chunk[..len])?;
+                dest.write_all(&chunk[..len])?;
+                remaining -= len;
+            }
+            let reply = read_final_reply(io, &mut |_| {})?;
+            match reply {
+                Reply::OKAY(_) => Ok(size as u64),
+                Reply::FAIL(message) => Err(FastbootError::Fail(message)),
+                _ => Err(FastbootError::Protocol("Unexpected reply".to_owned())),
+            }
+        }
+        Reply::FAIL(message) => Err(FastbootError::Fail(message)),
+        _ => Err(FastbootError::Protocol("Unexpected reply".to_owned())),
+    }
+}
+
+fn fb_command_lines<'a, T: Fastboot>(io: &'a mut T, cmd: &[u8]) -> CommandLines<'a, T> {
+    let first = send_raw_command(io, cmd);
+    CommandLines {
+        io,
+        first: Some(first),
+        done: false,
+    }
+}
+
+// Sends a command followed by a partition name, e.g. `flash:boot`, returning
+// the `OKAY` payload.
+fn fb_partition_command<T: Fastboot>(
+    io: &mut T,
+    cmd: &[u8],
+    partition: &str,
+    on_info: &mut dyn FnMut(&str),
+) -> FbResult<String> {
+    check_partition(partition)?;
+    let mut payload = Vec::with_capacity(cmd.len() + partition.len());
+    payload.extend_from_slice(cmd);
+    payload.extend_from_slice(partition.as_bytes());
+    let reply = send_command_with_info(io, &payload, on_info)?;
+    match reply {
+        Reply::OKAY(message) => Ok(message),
+        Reply::FAIL(message) => Err(FastbootError::Fail(message)),
+        _ => Err(FastbootError::Protocol("Unexpected reply".to_owned())),
+    }
+}
+
+// Refuses partition names that would make a malformed command. Colons are
+// fine, some clients use them to address storage, e.g. `mmc0:boot`.
+pub(crate) fn check_partition(partition: &str) -> FbResult<()> {
+    if partition.is_empty() {
+        return Err(FastbootError::Protocol(
+            "Partition must not be empty".to_owned(),
+        ));
+    }
+    if partition.chars().any(char::is_control) {
+        return Err(FastbootError::Protocol(format!(
+            "Invalid partition name: {:?}",
+            partition
+        )));
+    }
+    Ok(())
+}
+
+// Refuses downloads bigger than `limit`, the client's `max-download-size`,
+// unless the client doesn't report it.
+pub(crate) fn check_download_size(limit: Option<usize>, len: u64) -> FbResult<()> {
+    match limit {
+        Some(limit) if len > limit as u64 => Err(FastbootError::Protocol(format!(
+            "Download of {} bytes exceeds {} of {} bytes",
+            len, MAX_DOWNLOAD_SIZE_VAR, limit
+        ))),
+        _ => Ok(()),
+    }
+}
+
+// Takes a failure to get `max-download-size` for the client not reporting it,
+// in which case data is downloaded as is.
+pub(crate) fn known_limit(limit: FbResult<usize>) -> FbResult<Option<usize>> {
+    match limit {
+        Ok(limit) => Ok(Some(limit)),
+        Err(FastbootError::Fail(_)) | Err(FastbootError::Protocol(_)) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+// Gets the client's `max-download-size`, see `known_limit()`. It's queried
+// once per operation and passed down to every download the operation makes.
+pub(crate) fn download_limit<T: Fastboot>(io: &mut T) -> FbResult<Option<usize>> {
+    known_limit(io.max_download_size())
+}
+
+// Runs `transfer` of `bytes` bytes, timing it
+pub(crate) fn measure_transfer<F>(bytes: u64, transfer: F) -> FbResult<TransferStats>
+where
+    F: FnOnce() -> FbResult<()>,
+{
+    let start = Instant::now();
+    transfer()?;
+    Ok(TransferStats {
+        bytes,
+        elapsed: start.elapsed(),
+    })
+}
+
+// Appends the suffix of `slot`, the client's `current-slot`, to `base_partition`,
+// unless the client doesn't report it, i.e. isn't A/B.
+pub(crate) fn slot_partition(base_partition: &str, slot: FbResult<String>) -> FbResult<String> {
+    match slot {
+        Ok(ref slot) if !slot.is_empty() => Ok(format!(
+            "{}_{}",
+            base_partition,
+            slot.trim_start_matches('_')
+        )),
+        Ok(_) | Err(FastbootError::Fail(_)) => Ok(base_partition.to_owned()),
+        Err(err) => Err(err),
+    }
+}
+
+// Sends `download` for `data`, without checking it against `max-download-size`.
+pub(crate) fn fb_download<T: Fastboot>(
+    io: &mut T,
+    data: &[u8],
+    chunk_len: usize,
+    on_progress: &mut dyn FnMut(u64, u64),
+) -> FbResult<()> {
+    if chunk_len == 0 {
+        return Err(FastbootError::Protocol(
+            "Chunk size must not be zero".to_owned(),
+        ));
+    }
+
+    // Wrapped in block to drop len as soon as possible
+    let cmd = {
+        let mut cmd = Vec::with_capacity(DOWNLOAD_CMD.len() + 8);
+        let mut len = format!("{:08x}", data.len()).into_bytes();
+        cmd.extend_from_slice(DOWNLOAD_CMD);
+        cmd.append(&mut len);
+        cmd
+    };
+    let reply = send_command_with_info(io, &cmd, &mut |_| {})?;
+
+    match reply {
+        Reply::DATA(size) if size == data.len() => {
+            let mut sent = 0;
+            for chunk in data.chunks(chunk_len) {
+                io.write_all(chunk)?;
+                sent += chunk.len() as u64;
+                on_progress(sent, data.len() as u64);
+            }
+            // Lets USB transports terminate the transfer with a zero-length packet
+            io.flush()?;
+            let reply = read_final_reply(io, &mut |_| {})?;
+            match reply {
+                Reply::OKAY(_) => Ok(()),
+                Reply::FAIL(message) => Err(FastbootError::Fail(message)),
+                _ => Err(FastbootError::Protocol("Unexpected reply".to_owned())),
+            }
+        }
+        Reply::DATA(size) => Err(FastbootError::Protocol(format!(
+            "Device accepted {} bytes but {} were requested",
+            size,
+            data.len()
+        ))),
+        Reply::FAIL(message) => Err(FastbootError::Fail(message)),
+        _ => Err(FastbootError::Protocol("Unexpected reply".to_owned())),
+    }
+}
+
+// Sends `download` for `data` after checking it against `limit`.
+pub(crate) fn fb_download_checked<T: Fastboot>(
+    io: &mut T,
+    limit: Option<usize>,
+    data: &[u8],
+) -> FbResult<()> {
+    check_download_size(limit, data.len() as u64)?;
+    fb_download(io, data, DOWNLOAD_CHUNK_LEN, &mut |_, _| {})
+}
+
+// Sends `download` for `len` bytes copied from `source`, without checking it
+// against `max-download-size`.
+pub(crate) fn fb_download_stream<T: Fastboot, R: Read>(
+    io: &mut T,
+    mut source: R,
+    len: u64,
+) -> FbResult<()> {
+    let mut cmd = Vec::with_capacity(DOWNLOAD_CMD.len() + 8);
+    cmd.extend_from_slice(DOWNLOAD_CMD);
+    cmd.extend_from_slice(format!("{:08x}", len).as_bytes());
+    let reply = send_command_with_info(io, &cmd, &mut |_| {})?;
+
+    match reply {
+        Reply::DATA(size) if size as u64 == len => {
+            let mut chunk = vec![0; std::cmp::min(len, DOWNLOAD_CHUNK_LEN as u64) as usize];
+            let mut remaining = len;
+            while remaining > 0 {
+                let wanted = std::cmp::min(remaining, chunk.len() as u64) as usize;
+                let read = match source.read(&mut chunk[..wanted]) {
+                    Ok(0) => {
+                        return Err(FastbootError::Io(format!(
+                            "Source ended {} bytes short of {}",
+                            remaining, len
+                        )))
+                    }
+                    Ok(read) => read,
+                    Err(ref err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                    Err(err) => return Err(FastbootError::from(err)),
+                };
+                io.write_all(&chunk[..read])?;
+                remaining -= read as u64;
+            }
+            io.flush()?;
+            let reply = read_final_reply(io, &mut |_| {})?;
+            match reply {
+                Reply::OKAY(_) => Ok(()),
+                Reply::FAIL(message) => Err(FastbootError::Fail(message)),
+                _ => Err(FastbootError::Protocol("Unexpected reply".to_owned())),
+            }
+        }
+        Reply::DATA(size) => Err(FastbootError::Protocol(format!(
+            "Device accepted {} bytes but {} were requested",
+            size, len
+        ))),
+        Reply::FAIL(message) => Err(FastbootError::Fail(message)),
+        _ => Err(FastbootError::Protocol("Unexpected reply".to_owned())),
+    }
+}
+
+// Flashes a sparse image into a specified partition, see `Fastboot::flash_sparse`.
+pub(crate) fn fb_flash_sparse<T: Fastboot>(
+    io: &mut T,
+    limit: Option<usize>,
+    partition: &str,
+    image: &[u8],
+) -> FbResult<()> {
+    let parsed = SparseImage::parse(image)?;
+    let max_size = match limit {
+        Some(max_size) => max_size,
+        None => {
+            fb_download_checked(io, limit, image)?;
+            return io.flash(partition);
+        }
+    };
+    for sub_image in parsed.split(max_size)? {
+        fb_download_checked(io, limit, &sub_image)?;
+        io.flash(partition)?;
+    }
+    Ok(())
+}
+
+// Flashes data into a specified partition, see `Fastboot::flash_auto`.
+pub(crate) fn fb_flash_auto<T: Fastboot>(
+    io: &mut T,
+    limit: Option<usize>,
+    partition: &str,
+    data: &[u8],
+) -> FbResult<()> {
+    if sparse::is_sparse(data) {
+        return fb_flash_sparse(io, limit, partition, data);
+    }
+    let max_size = match limit {
+        Some(max_size) if data.len() > max_size => max_size,
+        _ => {
+            fb_download_checked(io, limit, data)?;
+            return io.flash(partition);
+        }
+    };
+
+    let block_size = sparse::DEFAULT_BLOCK_SIZE;
+    let block_len = block_size as usize;
+    let mut padded;
+    let data = if data.len().is_multiple_of(block_len) {
+        data
+    } else {
+        padded = data.to_vec();
+        padded.resize(data.len().div_ceil(block_len) * block_len, 0);
+        &padded
+    };
+    let image = SparseImage::from_raw(block_size, data)?;
+    for sub_image in image.split(max_size)? {
+        fb_download_checked(io, limit, &sub_image)?;
+        io.flash(partition)?;
+    }
+    Ok(())
+}
+
+// Flashes `len` bytes read from `reader` into a specified partition, see
+// `Fastboot::flash_file`. Clients that don't report `limit` get the data in
+// one go, as by `download`.
+pub(crate) fn fb_flash_reader<T: Fastboot, R: Read>(
+    io: &mut T,
+    limit: Option<usize>,
+    partition: &str,
+    mut reader: R,
+    len: usize,
+) -> FbResult<()> {
+    let mut magic = Vec::with_capacity(4);
+    (&mut reader).take(4).read_to_end(&mut magic)?;
+    let max_size = match limit {
+        Some(max_size) if !sparse::is_sparse(&magic) && len > max_size => max_size,
+        _ => {
+            let mut data = magic;
+            reader.read_to_end(&mut data)?;
+            return if sparse::is_sparse(&data) {
+                fb_flash_sparse(io, limit, partition, &data)
+            } else {
+                fb_download_checked(io, limit, &data)?;
+                io.flash(partition)
+            };
+        }
+    };
+
+    let block_size = sparse::DEFAULT_BLOCK_SIZE;
+    let chunk_len = sparse::raw_capacity(block_size, max_size);
+    if chunk_len == 0 {
+        return Err(FastbootError::Protocol(format!(
+            "{} of {} bytes is too small",
+            MAX_DOWNLOAD_SIZE_VAR, max_size
+        )));
+    }
+    let total_blocks = len.div_ceil(block_size as usize) as u32;
+    let mut start = 0;
+    let mut chunk = magic;
+    loop {
+        (&mut reader)
+            .take((chunk_len - chunk.len()) as u64)
+            .read_to_end(&mut chunk)?;
+        if chunk.is_empty() {
+            return Ok(());
+        }
+        let sub_image = sparse::wrap_raw(block_size, total_blocks, start, &chunk);
+        fb_download_checked(io, limit, &sub_image)?;
+        io.flash(partition)?;
+        start += (chunk.len() / block_size as usize) as u32;
+        chunk.clear();
+    }
+}
+
+// Flashes downloaded data into a specified partition at `offset` bytes from
+// its start, see `Fastboot::flash_at`.
+pub(crate) fn fb_flash_offset<T: Fastboot>(
+    io: &mut T,
+    partition: &str,
+    offset: u64,
+) -> FbResult<()> {
+    let target = format!("{}:0x{:08x}", partition, offset);
+    fb_partition_command(io, FLASH_CMD, &target, &mut |_| {}).map(|_| ())
+}
+
+// Sends `signature` for a downloaded signature, see `Fastboot::send_signature`.
+pub(crate) fn fb_signature<T: Fastboot>(io: &mut T) -> FbResult<()> {
+    let reply = send_command_with_info(io, SIGNATURE_CMD, &mut |_| {})?;
+    match reply {
+        Reply::OKAY(_) => Ok(()),
+        Reply::FAIL(message) => Err(FastbootError::Fail(message)),
+        _ => Err(FastbootError::Protocol("Unexpected reply".to_owned())),
+    }
+}
+
+// Runs `steps`, see `Fastboot::run_sequence`. Data is sent by `download`.
+pub(crate) fn fb_run_sequence<T: Fastboot>(
+    io: &mut T,
+    steps: &[Step],
+    download: &mut dyn FnMut(&mut T, &[u8]) -> FbResult<()>,
+) -> FbResult<Vec<StepResult>> {
+    let mut results = Vec::with_capacity(steps.len());
+    for step in steps {
+        if results.iter().any(|result| *result != StepResult::Done) {
+            results.push(StepResult::Skipped);
+            continue;
+        }
+        let result = match step {
+            Step::Erase(partition) => io.erase(partition),
+            Step::Download(data) => download(io, data),
+            Step::Flash(partition) => io.flash(partition),
+            Step::SetActive(slot) => io.set_active(slot),
+            Step::Oem(subcommand) => io.oem(subcommand).map(|_| ()),
+            Step::RebootBootloader => io.reboot_bootloader(),
+            Step::Reboot => io.reboot(),
+        };
+        results.push(match result {
+            Ok(()) => StepResult::Done,
+            Err(err @ FastbootError::Fail(_)) | Err(err @ FastbootError::Protocol(_)) => {
+                StepResult::Failed(err)
+            }
+            Err(err) => return Err(err),
+        });
+    }
+    Ok(results)
+}
+
+/// The `Fastboot` trait provides Fastboot-protocol host-side interface.
+///
+/// There are no required methods. The only requirement is that an object,
+/// implementing this trait implements also [`Read`], [`Write`] and [`Sized`] traits.
+pub trait Fastboot: Read + Write + Sized {
+    /// Gets a Fastboot variable.
+    ///
+    /// Clients may reply to variables they don't know with an empty value
+    /// rather than `FAIL`, so an empty value doesn't tell an unknown variable
+    /// from a known one that is blank. See [`getvar_opt`].
+    ///
+    /// NOTE: Fastboot variables aren't U-Boot environment variables.
+    ///
+    /// [`getvar_opt`]: #method.getvar_opt
+    fn getvar(&mut self, var: &str) -> FbResult<String> {
+        let mut cmd = Vec::with_capacity(GETVAR_CMD.len() + var.len());
+        cmd.extend_from_slice(GETVAR_CMD);
+        cmd.extend_from_slice(var.as_bytes());
+        let reply = send_command_with_info(self, &cmd, &mut |_| {})?;
+        match reply {
+            Reply::OKAY(variable) => Ok(variable),
+            Reply::FAIL(message) => Err(FastbootError::Fail(message)),
+            _ => Err(FastbootError::Protocol("Unexpected reply".to_owned())),
+        }
+    }
+
+    /// Same as [`getvar`], but gives `None` for an empty value, which is how
+    /// many clients report variables they don't know.
+    ///
+    /// [`getvar`]: #method.getvar
+    fn getvar_opt(&mut self, var: &str) -> FbResult<Option<String>> {
+        self.getvar(var)
+            .map(|value| Some(value).filter(|value| !value.is_empty()))
+    }
+
+    /// Gets a numeric Fastboot variable, either hex with a `0x` prefix or decimal.
+    fn getvar_u64(&mut self, var: &str) -> FbResult<u64> {
+        parse_var_number(var, &self.getvar(var)?)
+    }
+
+    /// Gets the version of the protocol a client implements, e.g. `(0, 4)`.
+    ///
+    /// A missing minor version is reported as 0. [`probe`] uses it to skip
+    /// variables that clients of older versions can't know. `TEXT` replies
+    /// are handled whatever the version is, as clients reporting the same one
+    /// differ in sending them.
+    ///
+    /// [`probe`]: #method.probe
+    fn protocol_version(&mut self) -> FbResult<(u16, u16)> {
+        parse_version(&self.getvar(VERSION_VAR)?)
+    }
+
+    /// Checks that a client still responds, e.g. after a long idle period or
+    /// while waiting for it to come back.
+    ///
+    /// This is just a convenience over [`getvar`] for `version`, which every
+    /// client knows, with the value discarded.
+    ///
+    /// [`getvar`]: #method.getvar
+    fn ping(&mut self) -> FbResult<()> {
+        self.getvar(VERSION_VAR).map(|_| ())
+    }
+
+    /// Checks whether a client is userspace Fastboot (fastbootd), which is what
+    /// flashes logical partitions.
+    ///
+    /// Bootloaders that don't know `is-userspace` predate fastbootd, so their
+    /// failure to report it gives `false`.
+    fn is_userspace(&mut self) -> FbResult<bool> {
+        match getvar_flag(self, IS_USERSPACE_VAR) {
+            Err(FastbootError::Fail(_)) => Ok(false),
+            result => result,
+        }
+    }
+
+    /// Gets the number of slots, e.g. 2 for A/B devices.
+    fn slot_count(&mut self) -> FbResult<u8> {
+        let count = self.getvar_u64(SLOT_COUNT_VAR)?;
+        u8::try_from(count)
+            .map_err(|_| FastbootError::Protocol(format!("Invalid {}: {}", SLOT_COUNT_VAR, count)))
+    }
+
+    /// Checks whether a partition has slots, i.e. is flashed as `<partition>_a`
+    /// and `<partition>_b` rather than as is.
+    fn has_slot(&mut self, partition: &str) -> FbResult<bool> {
+        getvar_flag(self, &format!("{}{}", HAS_SLOT_VAR, partition))
+    }
+
+    /// Checks that a client speaks Fastboot and gets what it supports, e.g.
+    /// before starting to flash.
+    ///
+    /// Variables a client doesn't know are left out, the rest must be valid.
+    /// Clients that report a protocol version older than 0.4 aren't asked
+    /// whether they're userspace Fastboot, which came with it.
+    fn probe(&mut self) -> FbResult<Capabilities> {
+        let protocol_version = match getvar_if_known(self, VERSION_VAR)? {
+            Some(version) => Some(parse_version(&version)?),
+            None => None,
+        };
+        let max_download_size = match getvar_if_known(self, MAX_DOWNLOAD_SIZE_VAR)? {
+            Some(size) => Some(parse_number(&size).ok_or_else(|| {
+                FastbootError::Protocol(format!("Invalid {}: {:?}", MAX_DOWNLOAD_SIZE_VAR, size))
+            })?),
+            None => None,
+        };
+        let current_slot = getvar_if_known(self, CURRENT_SLOT_VAR)?;
+        let is_userspace = if protocol_version.is_none_or(|version| version >= USERSPACE_VERSION) {
+            match getvar_if_known(self, IS_USERSPACE_VAR)? {
+                Some(value) => parse_flag(&value).ok_or_else(|| {
+                    FastbootError::Protocol(format!("Invalid {}: {:?}", IS_USERSPACE_VAR, value))
+                })?,
+                None => false,
+            }
+        } else {
+            false
+        };
+        Ok(Capabilities {
+            protocol_version,
+            max_download_size,
+            current_slot,
+            is_userspace,
+        })
+    }
+
+    /// Gets a well-known Fastboot variable, parsed into its type.
+    ///
+    /// Numbers and flags that can't be parsed, e.g. because a client replied
+    /// with an empty value to a variable it doesn't know, are reported as
+    /// [`FastbootError::Protocol`].
+    ///
+    /// [`FastbootError::Protocol`]: enum.FastbootError.html#variant.Protocol
+    fn getvar_typed(&mut self, var: &Var) -> FbResult<VarValue> {
+        let value = self.getvar(&var.name())?;
+        var.parse(value)
+    }
+
+    /// Gets all Fastboot variables reported by a client.
+    ///
+    /// Each `INFO` line is split on its first `:`, so values may contain `:`,
+    /// e.g. version strings. Variables that take an argument, such as
+    /// `partition-size:boot`, keep it in their name. Lines without a `:` are
+    /// stored with an empty value.
+    fn getvar_all(&mut self) -> FbResult<HashMap<String, String>> {
+        let mut reply = send_command(self, GETVAR_ALL_CMD)?;
+        let mut variables = HashMap::new();
+        loop {
+            match reply {
+                Reply::INFO(line) => {
+                    let (name, value) = split_var_line(&line);
+                    variables.insert(name.trim().to_owned(), value.trim().to_owned());
+                }
+                // Human-readable output isn't a variable
+                Reply::TEXT(_) => (),
+                _ => break,
+            }
+            reply = read_reply(self)?;
+        }
+        match reply {
+            Reply::OKAY(_) => Ok(variables),
+            Reply::FAIL(message) => Err(FastbootError::Fail(message)),
+            _ => Err(FastbootError::Protocol("Unexpected reply".to_owned())),
+        }
+    }
+
+    /// Formats the variables from [`getvar_all`] as a table with one aligned
+    /// `name = value` line per variable, sorted by name, e.g. for bug reports.
+    ///
+    /// [`getvar_all`]: #method.getvar_all
+    fn dump_vars(&mut self) -> FbResult<String> {
+        let variables: BTreeMap<_, _> = self.getvar_all()?.into_iter().collect();
+        let width = variables
+            .keys()
+            .map(|name| name.chars().count())
+            .max()
+            .unwrap_or(0);
+        let mut table = String::new();
+        for (name, value) in &variables {
+            table.push_str(&format!("{:<width$} = {}\n", name, value, width = width));
+        }
+        Ok(table)
+    }
+
+    /// Gets sizes and types of all partitions from `getvar all`, sorted by name.
+    ///
+    /// Partitions are listed if a client reports either of them, the other
+    /// one is `None` then. So is a size that isn't a number.
+    fn list_partitions(&mut self) -> FbResult<Vec<PartitionInfo>> {
+        let mut partitions = BTreeMap::new();
+        for (name, value) in self.getvar_all()? {
+            let (var, partition) = match split_partition_var(&name) {
+                Some(split) => split,
+                None => continue,
+            };
+            let info = partitions
+                .entry(partition.to_owned())
+                .or_insert_with(|| PartitionInfo {
+                    name: partition.to_owned(),
+                    size: None,
+                    partition_type: None,
+                });
+            if var == PARTITION_SIZE_VAR {
+                info.size = parse_number(&value);
+            } else {
+                info.partition_type = Some(value);
+            }
+        }
+        Ok(partitions.into_values().collect())
+    }
+
+    /// Gets the biggest payload a client accepts in a single [`download`].
+    ///
+    /// [`download`]: #method.download
+    fn max_download_size(&mut self) -> FbResult<usize> {
+        self.getvar_u64(MAX_DOWNLOAD_SIZE_VAR)
+            .map(|size| size as usize)
+    }
+
+    /// Gets the filesystem type of a partition, e.g. `ext4` or `raw`.
+    fn partition_type(&mut self, partition: &str) -> FbResult<String> {
+        self.getvar(&format!("{}{}", PARTITION_TYPE_VAR, partition))
+    }
+
+    /// Gets the size of a partition in bytes.
+    fn partition_size(&mut self, partition: &str) -> FbResult<u64> {
+        self.getvar_u64(&format!("{}{}", PARTITION_SIZE_VAR, partition))
+    }
+
+    /// Downloads provided data into a client.
+    ///
+    /// Data that exceeds the client's `max-download-size` is refused without
+    /// being sent. Clients that don't report the limit aren't checked. The
+    /// limit is queried on every call, methods that download several times,
+    /// e.g. [`flash_sparse`], query it once, and [`FastbootSession`] caches it.
+    ///
+    /// [`flash_sparse`]: #method.flash_sparse
+    /// [`FastbootSession`]: ../session/struct.FastbootSession.html
+    fn download(&mut self, data: &[u8]) -> FbResult<()> {
+        self.download_with_progress(data, &mut |_, _| {})
+    }
+
+    /// Downloads provided data into a client, calling `on_progress` with the
+    /// number of bytes sent so far and the total after every chunk.
+    fn download_with_progress(
+        &mut self,
+        data: &[u8],
+        on_progress: &mut dyn FnMut(u64, u64),
+    ) -> FbResult<()> {
+        check_download_size(download_limit(self)?, data.len() as u64)?;
+        fb_download(self, data, DOWNLOAD_CHUNK_LEN, on_progress)
+    }
+
+    /// Downloads provided data into a client in chunks of `chunk_size` bytes,
+    /// waiting for `delay` after each but the last.
+    ///
+    /// Meant for clients or hubs that reset when data comes at full rate. Use
+    /// [`download_with_progress`] to pace chunks in some other way.
+    ///
+    /// [`download_with_progress`]: #method.download_with_progress
+    fn download_throttled(
+        &mut self,
+        data: &[u8],
+        chunk_size: usize,
+        delay: Duration,
+    ) -> FbResult<()> {
+        check_download_size(download_limit(self)?, data.len() as u64)?;
+        fb_download(self, data, chunk_size, &mut |sent, total| {
+            if sent < total {
+                thread::sleep(delay);
+            }
+        })
+    }
+
+    /// Downloads `len` bytes read from `source` into a client, returning how
+    /// long it took.
+    ///
+    /// Unlike [`download`], data is copied in bounded chunks, so memory use
+    /// doesn't depend on the size of the download.
+    ///
+    /// [`download`]: #method.download
+    fn download_stream<R: Read>(&mut self, source: R, len: u64) -> FbResult<TransferStats> {
+        check_download_size(download_limit(self)?, len)?;
+        measure_transfer(len, || fb_download_stream(self, source, len))
+    }
+
+    /// Stages `data` for a following command that consumes it, e.g. a
+    /// vendor-specific one, as `fastboot stage` does.
+    ///
+    /// There's no separate command on the wire, `fastboot stage` sends a
+    /// [`download`] and so does this. It only tells staging apart from
+    /// downloading an image to flash. Data staged the other way, by a client,
+    /// is read with [`upload`] or [`get_staged_to_writer`].
+    ///
+    /// [`download`]: #method.download
+    /// [`upload`]: #method.upload
+    /// [`get_staged_to_writer`]: #method.get_staged_to_writer
+    fn stage(&mut self, data: &[u8]) -> FbResult<()> {
+        self.download(data)
+    }
+
+    /// Uploads data staged by a client, e.g. by a vendor-specific command.
+    fn upload(&mut self) -> FbResult<Vec<u8>> {
+        fb_upload(self, UPLOAD_CMD)
+    }
+
+    /// Uploads data staged by a client into `dest`, returning its size.
+    ///
+    /// Unlike [`upload`], data is written as it's received, so it's never
+    /// held in memory as a whole. This is what `fastboot get_staged` does.
+    ///
+    /// [`upload`]: #method.upload
+    fn get_staged_to_writer<W: Write>(&mut self, dest: W) -> FbResult<u64> {
+        fb_upload_to(self, UPLOAD_CMD, dest)
+    }
+
+    /// Reads back contents of a specified partition.
+    ///
+    /// `offset` and `size` limit what's read, the whole partition is read otherwise.
+    fn fetch(
+        &mut self,
+        partition: &str,
+        offset: Option<u64>,
+        size: Option<u64>,
+    ) -> FbResult<Vec<u8>> {
+        check_partition(partition)?;
+        let mut cmd = Vec::with_capacity(FETCH_CMD.len() + partition.len() + 2 * 19);
+        cmd.extend_from_slice(FETCH_CMD);
+        cmd.extend_from_slice(partition.as_bytes());
+        if offset.is_some() || size.is_some() {
+            cmd.extend_from_slice(format!(":0x{:08x}", offset.unwrap_or(0)).as_bytes());
+        }
+        if let Some(size) = size {
+            cmd.extend_from_slice(format!(":0x{:08x}", size).as_bytes());
+        }
+        fb_upload(self, &cmd)
+    }
+
+    /// Flashes downloaded data into a specified partition.
+    fn flash(&mut self, partition: &str) -> FbResult<()> {
+        self.flash_with_progress(partition, &mut |_| {})
+    }
+
+    /// Flashes downloaded data into a specified partition, passing every
+    /// `INFO` or `TEXT` line received from a client to `on_info`.
+    fn flash_with_progress(
+        &mut self,
+        partition: &str,
+        on_info: &mut dyn FnMut(&str),
+    ) -> FbResult<()> {
+        fb_partition_command(self, FLASH_CMD, partition, on_info).map(|_| ())
+    }
+
+    /// Flashes data staged by an earlier [`download`] into a specified partition.
+    ///
+    /// Same as [`flash`], named for code that downloads once and flashes the
+    /// staged data into several partitions. Nothing checks that a download
+    /// happened, clients flash whatever they have staged, if anything.
+    ///
+    /// [`download`]: #method.download
+    /// [`flash`]: #method.flash
+    fn flash_staged(&mut self, partition: &str) -> FbResult<()> {
+        self.flash(partition)
+    }
+
+    /// Downloads `data` and flashes it into a specified partition.
+    ///
+    /// The partition name is checked before anything is downloaded.
+    fn download_and_flash(&mut self, partition: &str, data: &[u8]) -> FbResult<()> {
+        check_partition(partition)?;
+        self.download(data)?;
+        self.flash(partition)
+    }
+
+    /// Downloads `data` and flashes it into a raw partition at `offset` bytes
+    /// from its start, e.g. to patch a single block.
+    ///
+    /// The standard `flash` command always writes from the start, so this
+    /// relies on a vendor extension taking the offset the same way as `fetch`,
+    /// i.e. `flash:<partition>:0x<offset>`. Clients without it either fail or
+    /// take the offset for a part of the name. Other forms can be sent with
+    /// [`run_command`] after a [`download`].
+    ///
+    /// [`run_command`]: fn.run_command.html
+    /// [`download`]: #method.download
+    fn flash_at(&mut self, partition: &str, offset: u64, data: &[u8]) -> FbResult<()> {
+        check_partition(partition)?;
+        self.download(data)?;
+        fb_flash_offset(self, partition, offset)
+    }
+
+    /// Checks whether an image of `data_len` bytes can be flashed into a
+    /// partition, without writing anything.
+    ///
+    /// The partition must be known and big enough, and the bootloader must be
+    /// unlocked. For sparse images `data_len` is the size they expand to.
+    fn flash_preflight(&mut self, partition: &str, data_len: u64) -> FbResult<PreflightReport> {
+        check_partition(partition)?;
+        let mut report = PreflightReport::default();
+
+        let size_var = format!("{}{}", PARTITION_SIZE_VAR, partition);
+        match self.getvar(&size_var) {
+            Ok(ref size) if size.is_empty() => {
+                report.warnings.push(PreflightIssue::Unchecked(size_var))
+            }
+            Ok(size) => match parse_number(&size) {
+                Some(size) if size < data_len => report.errors.push(PreflightIssue::TooBig(size)),
+                Some(_) => (),
+                None => report.warnings.push(PreflightIssue::Unchecked(size_var)),
+            },
+            Err(FastbootError::Fail(_)) => report.errors.push(PreflightIssue::UnknownPartition),
+            Err(err) => return Err(err),
+        }
+
+        match getvar_if_known(self, UNLOCKED_VAR)?
+            .as_deref()
+            .map(parse_flag)
+        {
+            Some(Some(true)) => (),
+            Some(Some(false)) => report.errors.push(PreflightIssue::Locked),
+            _ => report
+                .warnings
+                .push(PreflightIssue::Unchecked(UNLOCKED_VAR.to_owned())),
+        }
+        Ok(report)
+    }
+
+    /// Same as [`flash`], but returns the `OKAY` payload, which some clients use
+    /// to report details such as how long it took.
+    ///
+    /// [`flash`]: #method.flash
+    fn flash_verbose(&mut self, partition: &str) -> FbResult<String> {
+        fb_partition_command(self, FLASH_CMD, partition, &mut |_| {})
+    }
+
+    /// Downloads `data` and flashes it into `base_partition` of the active
+    /// slot, e.g. `boot_b` for `boot` when `current-slot` is `b`.
+    ///
+    /// Clients that don't report `current-slot` aren't A/B, so `base_partition`
+    /// is flashed as is.
+    fn flash_current_slot(&mut self, base_partition: &str, data: &[u8]) -> FbResult<()> {
+        check_partition(base_partition)?;
+        let partition = slot_partition(base_partition, self.getvar(CURRENT_SLOT_VAR))?;
+        self.download_and_flash(&partition, data)
+    }
+
+    /// Flashes an Android sparse image into a specified partition.
+    ///
+    /// The image is split into sub-images that fit into the client's
+    /// `max-download-size`, which are then downloaded and flashed one by one.
+    /// Clients that don't report the limit get the image as is.
+    fn flash_sparse(&mut self, partition: &str, image: &[u8]) -> FbResult<()> {
+        check_partition(partition)?;
+        let limit = download_limit(self)?;
+        fb_flash_sparse(self, limit, partition, image)
+    }
+
+    /// Flashes `data` into a specified partition, as `fastboot` does with
+    /// images that don't fit into the client's `max-download-size`.
+    ///
+    /// Raw data that doesn't fit is encoded by [`SparseImage::from_raw`],
+    /// padded with zeros to whole blocks, and flashed in sub-images like
+    /// sparse images are by [`flash_sparse`]. Anything else is flashed as is,
+    /// and so is everything if the client doesn't report the limit.
+    ///
+    /// [`SparseImage::from_raw`]: ../sparse/struct.SparseImage.html#method.from_raw
+    /// [`flash_sparse`]: #method.flash_sparse
+    fn flash_auto(&mut self, partition: &str, data: &[u8]) -> FbResult<()> {
+        check_partition(partition)?;
+        let limit = download_limit(self)?;
+        fb_flash_auto(self, limit, partition, data)
+    }
+
+    /// Flashes a file into a specified partition.
+    ///
+    /// Sparse images are handled by [`flash_sparse`]. Raw images that don't
+    /// fit into the client's `max-download-size` are read and flashed in
+    /// chunks, each wrapped into a sparse image so it lands at the right offset.
+    ///
+    /// Returns how long downloading and flashing took. Only the size of the
+    /// file is counted, not that of sparse image headers.
+    ///
+    /// [`flash_sparse`]: #method.flash_sparse
+    fn flash_file(&mut self, partition: &str, path: &Path) -> FbResult<TransferStats> {
+        check_partition(partition)?;
+        let file = File::open(path)?;
+        let len = file.metadata()?.len() as usize;
+        measure_transfer(len as u64, || {
+            let limit = download_limit(self)?;
+            fb_flash_reader(self, limit, partition, file, len)
+        })
+    }
+
+    /// Flashes an Android update package, as `fastboot update` does.
+    ///
+    /// Requirements listed in the package are checked first. See
+    /// [`UpdatePackage::flash_all`] for how images are flashed and when
+    /// `reconnect` is called.
+    ///
+    /// [`UpdatePackage::flash_all`]: ../update/struct.UpdatePackage.html#method.flash_all
+    #[cfg(feature = "zip")]
+    fn update_from_zip<F>(&mut self, path: &Path, reconnect: F) -> FbResult<()>
+    where
+        F: FnMut() -> FbResult<Self>,
+    {
+        let mut package = UpdatePackage::open(path)?;
+        package.check_requirements(self)?;
+        package.flash_all(self, reconnect)
+    }
+
+    /// Flashes every image of a build output directory, as planned by
+    /// [`plan_flash_dir`].
+    ///
+    /// Partitions are flashed without a slot suffix. The client drops the
+    /// connection when it reboots into a new bootloader, so `self` is
+    /// replaced by what `reconnect` returns after every reboot, as in
+    /// [`UpdatePackage::flash_all`]. To skip the reboots or only list what
+    /// would be done, get the plan and run the steps with [`run_flash_step`].
+    ///
+    /// [`plan_flash_dir`]: fn.plan_flash_dir.html
+    /// [`UpdatePackage::flash_all`]: ../update/struct.UpdatePackage.html#method.flash_all
+    /// [`run_flash_step`]: #method.run_flash_step
+    fn flash_dir<F>(&mut self, dir: &Path, mut reconnect: F) -> FbResult<()>
+    where
+        F: FnMut() -> FbResult<Self>,
+    {
+        for step in plan_flash_dir(dir)? {
+            self.run_flash_step(&step)?;
+            if let FlashStep::RebootBootloader = step {
+                *self = reconnect()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs a single step of a plan from [`plan_flash_dir`].
+    ///
+    /// The connection isn't usable after [`FlashStep::RebootBootloader`],
+    /// the caller has to reconnect before running the next step.
+    ///
+    /// [`plan_flash_dir`]: fn.plan_flash_dir.html
+    /// [`FlashStep::RebootBootloader`]: enum.FlashStep.html#variant.RebootBootloader
+    fn run_flash_step(&mut self, step: &FlashStep) -> FbResult<()> {
+        match step {
+            FlashStep::Flash { partition, image } => self.flash_file(partition, image).map(|_| ()),
+            FlashStep::RebootBootloader => self.reboot_bootloader(),
+        }
+    }
+
+    /// Runs `steps` in order, stopping at the first one that fails.
+    ///
+    /// Every step gets a result, those after a failure are [`Skipped`]. Steps
+    /// that fail with anything but [`FastbootError::Fail`] or
+    /// [`FastbootError::Protocol`], e.g. because a client went away, make the
+    /// whole sequence fail, as the state of the client is unknown.
+    ///
+    /// [`Skipped`]: enum.StepResult.html#variant.Skipped
+    /// [`FastbootError::Fail`]: enum.FastbootError.html#variant.Fail
+    /// [`FastbootError::Protocol`]: enum.FastbootError.html#variant.Protocol
+    fn run_sequence(&mut self, steps: &[Step]) -> FbResult<Vec<StepResult>> {
+        // Queried before the first download, if there's any
+        let mut known_limit = None;
+        fb_run_sequence(self, steps, &mut |io, data| {
+            let limit = match known_limit {
+                Some(limit) => limit,
+                None => *known_limit.insert(download_limit(io)?),
+            };
+            fb_download_checked(io, limit, data)
+        })
+    }
+
+    /// Erases a specified partition.
+    fn erase(&mut self, partition: &str) -> FbResult<()> {
+        self.erase_with_progress(partition, &mut |_| {})
+    }
+
+    /// Erases a specified partition, passing every `INFO` or `TEXT` line
+    /// received from a client to `on_info`.
+    fn erase_with_progress(
+        &mut self,
+        partition: &str,
+        on_info: &mut dyn FnMut(&str),
+    ) -> FbResult<()> {
+        fb_partition_command(self, ERASE_CMD, partition, on_info).map(|_| ())
+    }
+
+    /// Same as [`erase`], but returns the `OKAY` payload, see [`flash_verbose`].
+    ///
+    /// [`erase`]: #method.erase
+    /// [`flash_verbose`]: #method.flash_verbose
+    fn erase_verbose(&mut self, partition: &str) -> FbResult<String> {
+        fb_partition_command(self, ERASE_CMD, partition, &mut |_| {})
+    }
+
+    /// Sends a signature that secure clients check before allowing to flash.
+    ///
+    /// The signature is downloaded first, like any other data.
+    fn send_signature(&mut self, signature: &[u8]) -> FbResult<()> {
+        self.download(signature)?;
+        fb_signature(self)
+    }
+
+    /// Makes a client verify a flashed partition against its signature.
+    fn verify(&mut self, partition: &str) -> FbResult<()> {
+        let mut cmd = Vec::with_capacity(VERIFY_CMD.len() + partition.len());
+        cmd.extend_from_slice(VERIFY_CMD);
+        cmd.extend_from_slice(partition.as_bytes());
+        let reply = send_command_with_info(self, &cmd, &mut |_| {})?;
+        match reply {
+            Reply::OKAY(_) => Ok(()),
+            Reply::FAIL(message) => Err(FastbootError::Fail(message)),
+            _ => Err(FastbootError::Protocol("Unexpected reply".to_owned())),
+        }
+    }
+
+    /// Boots previously downloaded data without flashing it.
+    ///
+    /// NOTE: [`download`] must be called first to stage a boot image.
+    ///
+    /// [`download`]: #method.download
+    fn boot(&mut self) -> FbResult<()> {
+        // Some bootloaders report progress before the final reply
+        let reply = send_command_with_info(self, BOOT_CMD, &mut |_| {})?;
+        match reply {
+            Reply::OKAY(_) => Ok(()),
+            Reply::FAIL(message) => Err(FastbootError::Fail(message)),
+            _ => Err(FastbootError::Protocol("Unexpected reply".to_owned())),
+        }
+    }
+
+    /// Continues a client's normal boot process.
+    ///
+    /// NOTE: A client typically disconnects from USB right after replying, so
+    /// no further reads are done once `OKAY` is received.
+    fn continue_boot(&mut self) -> FbResult<()> {
+        let reply = send_command_with_info(self, CONTINUE_CMD, &mut |_| {})?;
+        match reply {
+            Reply::OKAY(_) => Ok(()),
+            Reply::FAIL(message) => Err(FastbootError::Fail(message)),
+            _ => Err(FastbootError::Protocol("Unexpected reply".to_owned())),
+        }
+    }
+
+    /// Reboots a client.
+    fn reboot(&mut self) -> FbResult<()> {
+        let reply = send_command_with_info(self, REBOOT_CMD, &mut |_| {})?;
+        match reply {
+            Reply::OKAY(_) => Ok(()),
+            Reply::FAIL(message) => Err(FastbootError::Fail(message)),
+            _ => Err(FastbootError::Protocol("Unexpected reply".to_owned())),
+        }
+    }
+
+    /// Reboots a client back into the bootloader.
+    fn reboot_bootloader(&mut self) -> FbResult<()> {
+        let reply = send_command_with_info(self, REBOOT_BOOTLOADER_CMD, &mut |_| {})?;
+        match reply {
+            Reply::OKAY(_) => Ok(()),
+            Reply::FAIL(message) => Err(FastbootError::Fail(message)),
+            _ => Err(FastbootError::Protocol("Unexpected reply".to_owned())),
+        }
+    }
+
+    /// Reboots a client into recovery.
+    fn reboot_recovery(&mut self) -> FbResult<()> {
+        let reply = send_command_with_info(self, REBOOT_RECOVERY_CMD, &mut |_| {})?;
+        match reply {
+            Reply::OKAY(_) => Ok(()),
+            Reply::FAIL(message) => Err(FastbootError::Fail(message)),
+            _ => Err(FastbootError::Protocol("Unexpected reply".to_owned())),
+        }
+    }
+
+    /// Reboots a client into userspace Fastboot (fastbootd), which is needed
+    /// to flash dynamic partitions.
+    ///
+    /// The client re-enumerates after rebooting, so a new connection has to be
+    /// opened, e.g. with `UsbContext::wait_for_device` from the `usbio` example
+    /// crate.
+    fn reboot_fastboot(&mut self) -> FbResult<()> {
+        let reply = send_command_with_info(self, REBOOT_FASTBOOT_CMD, &mut |_| {})?;
+        match reply {
+            Reply::OKAY(_) => Ok(()),
+            Reply::FAIL(message) => Err(FastbootError::Fail(message)),
+            _ => Err(FastbootError::Protocol("Unexpected reply".to_owned())),
+        }
+    }
+
+    /// Powers a client down.
+    ///
+    /// Not every client supports it, see [`FastbootError::is_unknown_command`].
+    ///
+    /// [`FastbootError::is_unknown_command`]: enum.FastbootError.html#method.is_unknown_command
+    fn powerdown(&mut self) -> FbResult<()> {
+        let reply = send_command_with_info(self, POWERDOWN_CMD, &mut |_| {})?;
+        match reply {
+            Reply::OKAY(_) => Ok(()),
+            Reply::FAIL(message) => Err(FastbootError::Fail(message)),
+            _ => Err(FastbootError::Protocol("Unexpected reply".to_owned())),
+        }
+    }
+
+    /// Marks a specified slot as active on A/B devices.
+    fn set_active(&mut self, slot: &str) -> FbResult<()> {
+        if slot.is_empty() {
+            return Err(FastbootError::Protocol("Slot must not be empty".to_owned()));
+        }
+
+        let mut cmd = Vec::with_capacity(SET_ACTIVE_CMD.len() + slot.len());
+        cmd.extend_from_slice(SET_ACTIVE_CMD);
+        cmd.extend_from_slice(slot.as_bytes());
+        let reply = send_command_with_info(self, &cmd, &mut |_| {})?;
+        match reply {
+            Reply::OKAY(_) => Ok(()),
+            Reply::FAIL(message) => Err(FastbootError::Fail(message)),
+            _ => Err(FastbootError::Protocol("Unexpected reply".to_owned())),
+        }
+    }
+
+    /// Runs a vendor-specific OEM command.
+    ///
+    /// Returns `INFO`/`TEXT` lines sent by a client, followed by the `OKAY` payload
+    /// (if any), joined by newlines.
+    fn oem(&mut self, subcommand: &str) -> FbResult<String> {
+        let mut cmd = Vec::with_capacity(OEM_CMD.len() + subcommand.len());
+        cmd.extend_from_slice(OEM_CMD);
+        cmd.extend_from_slice(subcommand.as_bytes());
+        run_command(self, &cmd).map(|outcome| outcome.to_string())
+    }
+
+    /// Sends a raw command, e.g. `oem dump-logs`, and iterates over the `INFO`
+    /// and `TEXT` lines a client sends in response as they're received.
+    ///
+    /// The iterator ends at `OKAY`, while `FAIL` or any other failure is its
+    /// last item. Use [`oem`] to collect everything at once instead.
+    ///
+    /// [`oem`]: #method.oem
+    fn command_lines(&mut self, cmd: &str) -> CommandLines<'_, Self> {
+        fb_command_lines(self, cmd.as_bytes())
+    }
+
+    /// Sends a raw command and returns the 4-byte prefix of the first reply
+    /// and its payload exactly as received, e.g. for probing undocumented
+    /// commands.
+    ///
+    /// Nothing is interpreted, so `FAIL` isn't an error and no more replies
+    /// are read after `INFO`, use [`read_reply`] for them. The payload may
+    /// not be valid UTF-8, and a reply shorter than a prefix has an empty one.
+    ///
+    /// [`read_reply`]: fn.read_reply.html
+    fn raw_command(&mut self, cmd: &[u8]) -> FbResult<(String, Vec<u8>)> {
+        let reply = send_raw_command(self, cmd)?;
+        let (prefix, payload) =
+            reply.split_at(std::cmp::min(reply.len(), protocol::REPLY_PREFIX_LEN));
+        Ok((
+            String::from_utf8_lossy(prefix).into_owned(),
+            payload.to_vec(),
+        ))
+    }
+
+    /// Runs a vendor-specific OEM command that dumps logs, e.g. `dump-logs`,
+    /// writing every line [`command_lines`] yields to `sink` followed by a
+    /// newline, so the log is never held in memory all at once.
+    ///
+    /// [`command_lines`]: #method.command_lines
+    fn capture_log<W: Write>(&mut self, subcommand: &str, mut sink: W) -> FbResult<()> {
+        let mut cmd = Vec::with_capacity(OEM_CMD.len() + subcommand.len());
+        cmd.extend_from_slice(OEM_CMD);
+        cmd.extend_from_slice(subcommand.as_bytes());
+        for line in fb_command_lines(self, &cmd) {
+            sink.write_all(line?.as_bytes())?;
+            sink.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+
+    /// Downloads `data` and then runs a vendor-specific OEM command that uses
+    /// it, e.g. to provision keys.
+    ///
+    /// Returns the same as [`oem`]. The command isn't run if the download fails.
+    ///
+    /// [`oem`]: #method.oem
+    fn oem_with_data(&mut self, subcommand: &str, data: &[u8]) -> FbResult<String> {
+        self.download(data)?;
+        self.oem(subcommand)
+    }
+
+    /// Unlocks the bootloader, allowing critical partitions to be flashed.
+    ///
+    /// Clients usually ask for a confirmation on the device and report the
+    /// prompt with `INFO` replies until it's given, so this may take a while.
+    fn flashing_unlock(&mut self) -> FbResult<()> {
+        let reply = send_command_with_info(self, FLASHING_UNLOCK_CMD, &mut |_| {})?;
+        match reply {
+            Reply::OKAY(_) => Ok(()),
+            Reply::FAIL(message) => Err(FastbootError::Fail(message)),
+            _ => Err(FastbootError::Protocol("Unexpected reply".to_owned())),
+        }
+    }
+
+    /// Locks the bootloader.
+    ///
+    /// Like [`flashing_unlock`], this usually needs a confirmation on the device.
+    ///
+    /// [`flashing_unlock`]: #method.flashing_unlock
+    fn flashing_lock(&mut self) -> FbResult<()> {
+        let reply = send_command_with_info(self, FLASHING_LOCK_CMD, &mut |_| {})?;
+        match reply {
+            Reply::OKAY(_) => Ok(()),
+            Reply::FAIL(message) => Err(FastbootError::Fail(message)),
+            _ => Err(FastbootError::Protocol("Unexpected reply".to_owned())),
+        }
+    }
+
+    /// Checks whether the bootloader is allowed to be unlocked.
+    ///
+    /// Clients report the `0`/`1` ability either in an `INFO` line such as
+    /// `get_unlock_ability: 1` or in the `OKAY` payload, both are accepted.
+    fn flashing_get_unlock_ability(&mut self) -> FbResult<bool> {
+        let mut ability = String::new();
+        let reply = send_command_with_info(self, FLASHING_GET_UNLOCK_ABILITY_CMD, &mut |line| {
+            ability = line.to_owned()
+        })?;
+        match reply {
+            Reply::OKAY(payload) => {
+                if !payload.is_empty() {
+                    ability = payload;
+                }
+                let value = ability.rsplit(':').next().unwrap_or("").trim();
+                match value {
+                    "1" => Ok(true),
+                    "0" => Ok(false),
+                    _ => Err(FastbootError::Protocol(format!(
+                        "Invalid unlock ability: {:?}",
+                        ability
+                    ))),
+                }
+            }
+            Reply::FAIL(message) => Err(FastbootError::Fail(message)),
+            _ => Err(FastbootError::Protocol("Unexpected reply".to_owned())),
+        }
+    }
+
+    /// Creates a logical partition of `size` bytes on devices with dynamic partitions.
+    fn create_logical_partition(&mut self, partition: &str, size: u64) -> FbResult<()> {
+        let size = size.to_string();
+        let mut cmd = Vec::with_capacity(
+            CREATE_LOGICAL_PARTITION_CMD.len() + partition.len() + 1 + size.len(),
+        );
+        cmd.extend_from_slice(CREATE_LOGICAL_PARTITION_CMD);
+        cmd.extend_from_slice(partition.as_bytes());
+        cmd.push(b':');
+        cmd.extend_from_slice(size.as_bytes());
+        let reply = send_command_with_info(self, &cmd, &mut |_| {})?;
+        match reply {
+            Reply::OKAY(_) => Ok(()),
+            Reply::FAIL(message) => Err(FastbootError::Fail(message)),
+            _ => Err(FastbootError::Protocol("Unexpected reply".to_owned())),
+        }
+    }
+
+    /// Deletes a logical partition on devices with dynamic partitions.
+    fn delete_logical_partition(&mut self, partition: &str) -> FbResult<()> {
+        let mut cmd = Vec::with_capacity(DELETE_LOGICAL_PARTITION_CMD.len() + partition.len());
+        cmd.extend_from_slice(DELETE_LOGICAL_PARTITION_CMD);
+        cmd.extend_from_slice(partition.as_bytes());
+        let reply = send_command_with_info(self, &cmd, &mut |_| {})?;
+        match reply {
+            Reply::OKAY(_) => Ok(()),
+            Reply::FAIL(message) => Err(FastbootError::Fail(message)),
+            _ => Err(FastbootError::Protocol("Unexpected reply".to_owned())),
+        }
+    }
+
+    /// Resizes a logical partition to `size` bytes on devices with dynamic partitions.
+    fn resize_logical_partition(&mut self, partition: &str, size: u64) -> FbResult<()> {
+        let size = size.to_string();
+        let mut cmd = Vec::with_capacity(
+            RESIZE_LOGICAL_PARTITION_CMD.len() + partition.len() + 1 + size.len(),
+        );
+        cmd.extend_from_slice(RESIZE_LOGICAL_PARTITION_CMD);
+        cmd.extend_from_slice(partition.as_bytes());
+        cmd.push(b':');
+        cmd.extend_from_slice(size.as_bytes());
+        let reply = send_command_with_info(self, &cmd, &mut |_| {})?;
         match reply {
             Reply::OKAY(_) => Ok(()),
-            Reply::FAIL(message) => Err(message),
-            _ => Err("Unknown failure".to_owned()),
+            Reply::FAIL(message) => Err(FastbootError::Fail(message)),
+            _ => Err(FastbootError::Protocol("Unexpected reply".to_owned())),
         }
     }
 }
@@ -1,12 +1,59 @@
+//! Host side of the Fastboot protocol.
+//!
+//! There's no `Transport` trait, [`Fastboot`] is implemented for every type
+//! that is [`Read`] and [`Write`], which is all a transport has to provide.
+//! A trait of its own would only repeat those two, and would keep types from
+//! other crates, e.g. a serial port or an in-memory mock, from being used
+//! without a wrapper. The bundled transports are picked with features:
+//! `tcp` (on by default) for [`tcpio::TcpDevice`] and `usb` for the libusb
+//! based [`usb::UsbDevice`]. Without either, the crate pulls in no transport
+//! dependencies.
+//!
+//! [`Fastboot`]: fastboot/trait.Fastboot.html
+//! [`Read`]: https://doc.rust-lang.org/std/io/trait.Read.html
+//! [`Write`]: https://doc.rust-lang.org/std/io/trait.Write.html
+//! [`tcpio::TcpDevice`]: tcpio/struct.TcpDevice.html
+//! [`usb::UsbDevice`]: usb/struct.UsbDevice.html
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(feature = "async")]
+pub mod async_fastboot;
+#[cfg(feature = "std")]
 pub mod fastboot;
+pub mod protocol;
+#[cfg(feature = "test-util")]
+pub mod scripted;
+#[cfg(feature = "std")]
+pub mod session;
+#[cfg(feature = "std")]
+pub mod sparse;
+#[cfg(feature = "tcp")]
+pub mod tcpio;
+#[cfg(all(feature = "std", feature = "zip"))]
+pub mod update;
 
-#[cfg(test)]
+/// USB transport built on libusb, enabled by the `usb` feature.
+#[cfg(feature = "usb")]
+pub use usbio::usbio as usb;
+
+#[cfg(all(test, feature = "std"))]
 mod tests {
-    use crate::fastboot::Fastboot;
-    use std::cell::RefCell;
+    use crate::fastboot::{
+        self, Capabilities, Fastboot, FastbootError, Line, PartitionInfo, PreflightIssue,
+        PreflightReport, Reply, Step, StepResult, TransferStats, Var, VarValue,
+    };
+    use crate::protocol::{self, DecodeError, RawReply};
+    use crate::session::FastbootSession;
+    use crate::sparse::{self, ChunkData, SparseImage};
+    use std::cell::Cell;
+    use std::collections::HashMap;
     use std::error::Error;
     use std::fmt;
+    use std::fs;
     use std::io;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
 
     extern crate double;
     use self::double::Mock;
@@ -31,7 +78,8 @@ mod tests {
 
     #[derive(Debug, Clone)]
     struct MockUsb {
-        pub read: Mock<*mut u8, Result<usize, CloneableError>>,
+        // Called with the length of the buffer to read into
+        pub read: Mock<usize, Result<Vec<u8>, CloneableError>>,
         pub write: Mock<Vec<u8>, Result<usize, CloneableError>>,
         pub flush: Mock<(), Result<(), CloneableError>>,
     }
@@ -39,7 +87,7 @@ mod tests {
     impl Default for MockUsb {
         fn default() -> Self {
             MockUsb {
-                read: Mock::new(Ok(0)),
+                read: Mock::new(Ok(Vec::new())),
                 write: Mock::new(Ok(0)),
                 flush: Mock::new(Ok(())),
             }
@@ -52,11 +100,19 @@ mod tests {
                 return Ok(0);
             }
 
-            let arg = buf.as_mut_ptr();
-            self.read.call(arg).map_err(|err| {
+            let arg = buf.len();
+            let reply = self.read.call(arg).map_err(|err| {
                 let (kind, description) = (err.kind, err.description);
                 io::Error::new(kind, description)
-            })
+            })?;
+            assert!(
+                reply.len() <= buf.len(),
+                "Reply of {} bytes doesn't fit into {}",
+                reply.len(),
+                buf.len()
+            );
+            buf[..reply.len()].copy_from_slice(&reply);
+            Ok(reply.len())
         }
     }
 
@@ -82,57 +138,849 @@ mod tests {
         }
     }
 
+    // Replies with each of `replies` in turn, repeating the last one
+    fn reply_sequence(
+        replies: Vec<&'static str>,
+    ) -> Box<dyn Fn(usize) -> Result<Vec<u8>, CloneableError>> {
+        let index = Cell::new(0);
+        Box::new(move |_| {
+            let reply = replies[std::cmp::min(index.get(), replies.len() - 1)];
+            index.set(index.get() + 1);
+            Ok(reply.as_bytes().to_vec())
+        })
+    }
+
     #[test]
     fn test_getvar() {
         let mut mock = MockUsb::default();
 
         mock.write
             .return_value_for("getvar:version".as_bytes(), Ok(14));
-        mock.read.use_closure(Box::new(|buf| {
-            let reply = "OKAY1.0";
-            unsafe { reply.as_ptr().copy_to_nonoverlapping(buf, reply.len()) };
-            Ok(reply.len())
-        }));
+        mock.read
+            .use_closure(Box::new(|_| Ok("OKAY1.0".as_bytes().to_vec())));
         assert_eq!(Ok("1.0".to_owned()), mock.getvar("version"));
 
         mock.write
             .return_value_for("getvar:something".as_bytes(), Ok(16));
-        mock.read.use_closure(Box::new(|buf| {
-            let reply = "FAIL";
-            unsafe { reply.as_ptr().copy_to_nonoverlapping(buf, reply.len()) };
-            Ok(reply.len())
+        mock.read
+            .use_closure(Box::new(|_| Ok("FAIL".as_bytes().to_vec())));
+        assert_eq!(
+            Err(FastbootError::Fail("".to_owned())),
+            mock.getvar("something")
+        );
+    }
+
+    #[test]
+    fn test_getvar_opt() {
+        let mut mock = MockUsb::default();
+
+        mock.write.use_closure(Box::new(|buf| Ok(buf.len())));
+        mock.read.use_closure(reply_sequence(vec!["OKAYsailfish"]));
+        assert_eq!(Ok(Some("sailfish".to_owned())), mock.getvar_opt("product"));
+        mock.read.use_closure(reply_sequence(vec!["OKAY"]));
+        assert_eq!(Ok(None), mock.getvar_opt("unknown"));
+        mock.read
+            .use_closure(reply_sequence(vec!["FAILunknown variable"]));
+        assert_eq!(
+            Err(FastbootError::Fail("unknown variable".to_owned())),
+            mock.getvar_opt("unknown")
+        );
+    }
+
+    #[test]
+    fn test_long_reply() {
+        let mut mock = MockUsb::default();
+
+        // Longer than a full-speed packet, yet a single high-speed one
+        let value = "x".repeat(100);
+        let reply = format!("OKAY{}", value);
+        mock.write
+            .return_value_for("getvar:serialno".as_bytes(), Ok(15));
+        mock.read
+            .use_closure(Box::new(move |_| Ok(reply.as_bytes().to_vec())));
+        assert_eq!(Ok(value), mock.getvar("serialno"));
+    }
+
+    #[test]
+    fn test_split_reply() {
+        let mut mock = MockUsb::default();
+
+        // A reply that fills a whole read goes on in the next one
+        let value = "x".repeat(508) + &"y".repeat(100);
+        let reply = format!("OKAY{}", value);
+        let offset = Cell::new(0);
+        mock.write
+            .return_value_for("getvar:serialno".as_bytes(), Ok(15));
+        mock.read.use_closure(Box::new(move |len| {
+            let part = &reply.as_bytes()[offset.get()..];
+            let len = std::cmp::min(part.len(), len);
+            offset.set(offset.get() + len);
+            Ok(part[..len].to_vec())
+        }));
+        assert_eq!(Ok(value), mock.getvar("serialno"));
+        assert_eq!(2, mock.read.num_calls());
+
+        // A reply that happens to fill it doesn't need anything to follow
+        let value = "x".repeat(508);
+        let reply = format!("OKAY{}", value);
+        let calls = Cell::new(0);
+        mock.read.reset_calls();
+        mock.read.use_closure(Box::new(move |_| {
+            calls.set(calls.get() + 1);
+            if calls.get() > 1 {
+                return Err(CloneableError {
+                    kind: io::ErrorKind::TimedOut,
+                    description: "timed out".to_owned(),
+                });
+            }
+            Ok(reply.as_bytes().to_vec())
         }));
-        assert_eq!(Err("".to_owned()), mock.getvar("something"));
+        assert_eq!(Ok(value), mock.getvar("serialno"));
+    }
+
+    #[test]
+    fn test_getvar_invalid_utf8() {
+        let mut mock = MockUsb::default();
+
+        mock.write
+            .return_value_for("getvar:serialno".as_bytes(), Ok(15));
+        mock.read
+            .use_closure(Box::new(|_| Ok(b"OKAY\xffab\xc3".to_vec())));
+        assert_eq!(Ok("\u{fffd}ab\u{fffd}".to_owned()), mock.getvar("serialno"));
+    }
+
+    #[test]
+    fn test_getvar_u64() {
+        let mut mock = MockUsb::default();
+
+        mock.write
+            .return_value_for("getvar:max-download-size".as_bytes(), Ok(24));
+        mock.read
+            .use_closure(Box::new(|_| Ok("OKAY0x10000000".as_bytes().to_vec())));
+        assert_eq!(Ok(0x1000_0000), mock.getvar_u64("max-download-size"));
+
+        mock.write
+            .return_value_for("getvar:product".as_bytes(), Ok(14));
+        mock.read
+            .use_closure(Box::new(|_| Ok("OKAYboard".as_bytes().to_vec())));
+        match mock.getvar_u64("product") {
+            Err(FastbootError::Protocol(_)) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_protocol_version() {
+        let mut mock = MockUsb::default();
+
+        mock.write
+            .return_value_for("getvar:version".as_bytes(), Ok(14));
+        mock.read.use_closure(reply_sequence(vec!["OKAY0.4"]));
+        assert_eq!(Ok((0, 4)), mock.protocol_version());
+        mock.read.use_closure(reply_sequence(vec!["OKAY1"]));
+        assert_eq!(Ok((1, 0)), mock.protocol_version());
+
+        for version in &["OKAY", "OKAYv1.0", "OKAY0.4.1", "OKAY0.x"] {
+            mock.read.use_closure(reply_sequence(vec![version]));
+            match mock.protocol_version() {
+                Err(FastbootError::Protocol(_)) => (),
+                result => panic!("Unexpected result: {:?}", result),
+            }
+        }
+    }
+
+    #[test]
+    fn test_slot_vars() {
+        let mut mock = MockUsb::default();
+
+        mock.write.use_closure(Box::new(|buf| Ok(buf.len())));
+        mock.read.use_closure(reply_sequence(vec!["OKAYyes"]));
+        assert_eq!(Ok(true), mock.is_userspace());
+        assert_eq!(Ok(true), mock.has_slot("boot"));
+        assert!(mock.write.called_with("getvar:has-slot:boot".as_bytes()));
+        mock.read.use_closure(reply_sequence(vec!["OKAYno"]));
+        assert_eq!(Ok(false), mock.is_userspace());
+        assert_eq!(Ok(false), mock.has_slot("misc"));
+        mock.read.use_closure(reply_sequence(vec!["OKAY2"]));
+        assert_eq!(Ok(2), mock.slot_count());
+
+        // Bootloaders that predate fastbootd don't know about it
+        mock.read
+            .use_closure(reply_sequence(vec!["FAILunknown variable"]));
+        assert_eq!(Ok(false), mock.is_userspace());
+        assert!(mock.has_slot("boot").is_err());
+        assert!(mock.slot_count().is_err());
+
+        for value in &["OKAY", "OKAYtrue", "OKAY0x100"] {
+            mock.read.use_closure(reply_sequence(vec![value]));
+            for result in &[
+                mock.is_userspace().map(|_| ()),
+                mock.has_slot("boot").map(|_| ()),
+                mock.slot_count().map(|_| ()),
+            ] {
+                match result {
+                    Err(FastbootError::Protocol(_)) => (),
+                    result => panic!("Unexpected result for {}: {:?}", value, result),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_probe() {
+        let mut mock = MockUsb::default();
+
+        mock.write.use_closure(Box::new(|buf| Ok(buf.len())));
+        mock.read.use_closure(reply_sequence(vec![
+            "OKAY0.4",
+            "OKAY0x10000000",
+            "OKAYa",
+            "OKAYyes",
+        ]));
+        assert_eq!(
+            Ok(Capabilities {
+                protocol_version: Some((0, 4)),
+                max_download_size: Some(0x10000000),
+                current_slot: Some("a".to_owned()),
+                is_userspace: true,
+            }),
+            mock.probe()
+        );
+        assert!(mock.write.called_with("getvar:is-userspace".as_bytes()));
+
+        // Older bootloaders know few variables
+        mock.read.use_closure(reply_sequence(vec![
+            "OKAY0.4",
+            "FAILunknown variable",
+            "OKAY",
+            "FAILunknown variable",
+        ]));
+        assert_eq!(
+            Ok(Capabilities {
+                protocol_version: Some((0, 4)),
+                ..Capabilities::default()
+            }),
+            mock.probe()
+        );
+
+        // Clients older than userspace Fastboot aren't asked about it
+        mock.write.reset_calls();
+        mock.read.use_closure(reply_sequence(vec![
+            "OKAY0.3",
+            "OKAY0x1000",
+            "FAILunknown variable",
+        ]));
+        assert_eq!(
+            Ok(Capabilities {
+                protocol_version: Some((0, 3)),
+                max_download_size: Some(0x1000),
+                ..Capabilities::default()
+            }),
+            mock.probe()
+        );
+        assert!(!mock.write.called_with("getvar:is-userspace".as_bytes()));
+
+        // Flags must be either yes or no
+        mock.read.use_closure(reply_sequence(vec![
+            "OKAY0.4",
+            "OKAY0x1000",
+            "OKAYa",
+            "OKAYmaybe",
+        ]));
+        match mock.probe() {
+            Err(FastbootError::Protocol(_)) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+
+        // Anything but Fastboot
+        mock.read.use_closure(reply_sequence(vec!["WHATever"]));
+        assert!(mock.probe().is_err());
+    }
+
+    #[test]
+    fn test_getvar_typed() {
+        let mut mock = MockUsb::default();
+
+        mock.write.use_closure(Box::new(|buf| Ok(buf.len())));
+        mock.read.use_closure(reply_sequence(vec!["OKAY0x1000"]));
+        assert_eq!(
+            Ok(VarValue::Number(4096)),
+            mock.getvar_typed(&Var::MaxDownloadSize)
+        );
+        mock.read.use_closure(reply_sequence(vec!["OKAYyes"]));
+        assert_eq!(
+            Ok(VarValue::Flag(true)),
+            mock.getvar_typed(&Var::HasSlot("boot".to_owned()))
+        );
+        assert!(mock.write.called_with("getvar:has-slot:boot".as_bytes()));
+        mock.read.use_closure(reply_sequence(vec!["OKAYsailfish"]));
+        assert_eq!(
+            Ok(VarValue::Text("sailfish".to_owned())),
+            mock.getvar_typed(&Var::Product)
+        );
+
+        // Unknown variables are reported as empty
+        mock.read.use_closure(reply_sequence(vec!["OKAY"]));
+        match mock.getvar_typed(&Var::IsLogical("system".to_owned())) {
+            Err(FastbootError::Protocol(_)) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        assert!(mock
+            .write
+            .called_with("getvar:is-logical:system".as_bytes()));
+    }
+
+    #[test]
+    fn test_getvar_all() {
+        let mut mock = MockUsb::default();
+
+        mock.write.return_value_for("getvar:all".as_bytes(), Ok(10));
+        mock.read.use_closure(reply_sequence(vec![
+            "INFOversion:0.4",
+            "INFOpartition-size:boot: 0x1000",
+            "INFOpartition-type:system_a:ext4",
+            "INFOversion-bootloader: 1.2:rc3",
+            "INFOsecure",
+            "OKAY",
+        ]));
+        let mut expected = HashMap::new();
+        expected.insert("version".to_owned(), "0.4".to_owned());
+        expected.insert("partition-size:boot".to_owned(), "0x1000".to_owned());
+        expected.insert("partition-type:system_a".to_owned(), "ext4".to_owned());
+        expected.insert("version-bootloader".to_owned(), "1.2:rc3".to_owned());
+        expected.insert("secure".to_owned(), "".to_owned());
+        assert_eq!(Ok(expected), mock.getvar_all());
+
+        mock.read
+            .use_closure(reply_sequence(vec!["INFOversion:0.4", "FAIL"]));
+        assert_eq!(Err(FastbootError::Fail("".to_owned())), mock.getvar_all());
+    }
+
+    #[test]
+    fn test_dump_vars() {
+        let mut mock = MockUsb::default();
+
+        mock.write.return_value_for("getvar:all".as_bytes(), Ok(10));
+        mock.read.use_closure(reply_sequence(vec![
+            "INFOversion:0.4",
+            "INFOpartition-size:boot: 0x1000",
+            "INFOsecure",
+            "OKAY",
+        ]));
+        assert_eq!(
+            Ok("partition-size:boot = 0x1000\n\
+                secure              = \n\
+                version             = 0.4\n"
+                .to_owned()),
+            mock.dump_vars()
+        );
+    }
+
+    #[test]
+    fn test_list_partitions() {
+        let mut mock = MockUsb::default();
+
+        mock.write.return_value_for("getvar:all".as_bytes(), Ok(10));
+        mock.read.use_closure(reply_sequence(vec![
+            "INFOversion:0.4",
+            "INFOpartition-size:system_a: 0x40000000",
+            "INFOpartition-type:system_a: ext4",
+            "INFOpartition_size: boot_a: 0x4000000",
+            "INFOpartition-type:misc:raw",
+            "INFOpartition-size:userdata:unknown",
+            "OKAY",
+        ]));
+        let partition = |name: &str, size, partition_type: Option<&str>| PartitionInfo {
+            name: name.to_owned(),
+            size,
+            partition_type: partition_type.map(str::to_owned),
+        };
+        assert_eq!(
+            Ok(vec![
+                partition("boot_a", Some(0x4000000), None),
+                partition("misc", None, Some("raw")),
+                partition("system_a", Some(0x40000000), Some("ext4")),
+                partition("userdata", None, None),
+            ]),
+            mock.list_partitions()
+        );
     }
 
     #[test]
     fn test_download() {
         let mut mock = MockUsb::default();
 
+        mock.write
+            .return_value_for("getvar:max-download-size".as_bytes(), Ok(24));
         mock.write
             .return_value_for("download:00000004".as_bytes(), Ok(17));
         mock.write.return_value_for("data".as_bytes(), Ok(4));
-        let flag = RefCell::new(false);
-        mock.read.use_closure(Box::new(move |buf| {
-            let reply = if !*flag.borrow() {
-                *flag.borrow_mut() = true;
-                "DATA00000004"
-            } else {
-                "OKAY"
-            };
-            unsafe { reply.as_ptr().copy_to_nonoverlapping(buf, reply.len()) };
-            Ok(reply.len())
-        }));
+        mock.read
+            .use_closure(reply_sequence(vec!["OKAY0x1000", "DATA00000004", "OKAY"]));
         assert_eq!(Ok(()), mock.download("data".as_bytes()));
+        // Lets the transport terminate the transfer, e.g. with a zero-length packet
+        assert!(mock.flush.called());
 
         mock.write
             .return_value_for("download:00000400".as_bytes(), Ok(17));
-        mock.read.use_closure(Box::new(move |buf| {
-            let reply = "FAIL";
-            unsafe { reply.as_ptr().copy_to_nonoverlapping(buf, reply.len()) };
-            Ok(reply.len())
+        mock.read
+            .use_closure(Box::new(|_| Ok("FAIL".as_bytes().to_vec())));
+        assert_eq!(
+            Err(FastbootError::Fail("".to_owned())),
+            mock.download(&vec![0; 1024])
+        );
+
+        // A client clamping the size must not be sent the data
+        mock.write.reset_calls();
+        mock.read
+            .use_closure(reply_sequence(vec!["OKAY0x1000", "DATA00000002"]));
+        assert_eq!(
+            Err(FastbootError::Protocol(
+                "Device accepted 2 bytes but 4 were requested".to_owned()
+            )),
+            mock.download("data".as_bytes())
+        );
+        assert!(!mock.write.called_with("data".as_bytes()));
+
+        // Lines reported after the data phase precede the final reply
+        mock.read.use_closure(reply_sequence(vec![
+            "OKAY0x1000",
+            "DATA00000004",
+            "INFOchecksum ok",
+            "OKAY",
+        ]));
+        assert_eq!(Ok(()), mock.download("data".as_bytes()));
+    }
+
+    #[test]
+    fn test_session() {
+        let mock = MockUsb::default();
+
+        mock.write.use_closure(Box::new(|buf| Ok(buf.len())));
+        mock.read.use_closure(reply_sequence(vec![
+            "OKAY0x1000",
+            "DATA00000004",
+            "OKAY",
+            "DATA00000004",
+            "OKAY",
+            "OKAYa",
+            "OKAY",
+        ]));
+        let mut session = FastbootSession::new(mock);
+        assert_eq!(Ok(()), session.download("data".as_bytes()));
+        assert_eq!(Ok(()), session.download("data".as_bytes()));
+        assert_eq!(Ok(4096), session.max_download_size());
+        match session.download(&vec![0; 4097]) {
+            Err(FastbootError::Protocol(_)) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        assert_eq!(Ok("a".to_owned()), session.current_slot());
+        assert_eq!(Ok(()), session.set_active("b"));
+        assert_eq!(Ok("b".to_owned()), session.current_slot());
+
+        let mock = session.into_inner();
+        let queries = |var: &str| {
+            let cmd = format!("getvar:{}", var).into_bytes();
+            mock.write
+                .calls()
+                .iter()
+                .filter(|call| **call == cmd)
+                .count()
+        };
+        assert_eq!(1, queries("max-download-size"));
+        assert_eq!(1, queries("current-slot"));
+    }
+
+    #[test]
+    fn test_session_flash() {
+        let mock = MockUsb::default();
+
+        mock.write.use_closure(Box::new(|buf| Ok(buf.len())));
+        mock.read.use_closure(reply_sequence(vec![
+            "OKAY0x1000",
+            "DATA00000054",
+            "OKAY",
+            "OKAY",
+            "DATA00000054",
+            "OKAY",
+            "OKAY",
+            "DATA00000004",
+            "OKAY",
+            "OKAY",
+        ]));
+        let mut session = FastbootSession::new(mock);
+        assert_eq!(Ok(()), session.flash_sparse("system", &sparse_image()));
+        assert_eq!(Ok(()), session.flash_auto("vendor", &sparse_image()));
+        assert_eq!(
+            Ok(vec![StepResult::Done, StepResult::Done]),
+            session.run_sequence(&[
+                Step::Download(b"data".to_vec()),
+                Step::Flash("boot".to_owned())
+            ])
+        );
+
+        let mock = session.into_inner();
+        assert_eq!(
+            1,
+            mock.write
+                .calls()
+                .iter()
+                .filter(|call| *call == b"getvar:max-download-size")
+                .count()
+        );
+        assert!(mock.write.called_with("flash:vendor".as_bytes()));
+    }
+
+    #[test]
+    fn test_reply_buffer_len() {
+        let mock = MockUsb::default();
+        let value = "a".repeat(700);
+
+        mock.write
+            .return_value_for("getvar:serialno".as_bytes(), Ok(15));
+        let reply = format!("OKAY{}", value);
+        mock.read
+            .use_closure(Box::new(move |_| Ok(reply.as_bytes().to_vec())));
+        let mut session = FastbootSession::new(mock);
+        for len in &[3, 511] {
+            match session.set_reply_buffer_len(*len) {
+                Err(FastbootError::Protocol(_)) => (),
+                result => panic!("Unexpected result: {:?}", result),
+            }
+        }
+        assert_eq!(Ok(()), session.set_reply_buffer_len(1024));
+        assert_eq!(Ok(value), session.getvar("serialno"));
+        assert_eq!(1, session.get_ref().read.num_calls());
+
+        // Session methods use it too
+        let line = format!("INFO{}", "b".repeat(700));
+        let replies = vec!["OKAY0x1000", "DATA00000004", &line, "OKAY"]
+            .into_iter()
+            .map(|reply| reply.as_bytes().to_vec())
+            .collect::<Vec<_>>();
+        let index = Cell::new(0);
+        session
+            .get_ref()
+            .write
+            .use_closure(Box::new(|buf| Ok(buf.len())));
+        session.get_ref().read.use_closure(Box::new(move |_| {
+            index.set(index.get() + 1);
+            Ok(replies[index.get() - 1].clone())
+        }));
+        assert_eq!(Ok(()), session.download(b"data"));
+    }
+
+    #[test]
+    fn test_cancel() {
+        let mock = MockUsb::default();
+        let cancel = Arc::new(AtomicBool::new(false));
+
+        // Cancelled while a long erase keeps timing out
+        mock.write.use_closure(Box::new(|buf| Ok(buf.len())));
+        let flag = cancel.clone();
+        mock.read.use_closure(Box::new(move |_| {
+            flag.store(true, Ordering::SeqCst);
+            Err(CloneableError {
+                kind: io::ErrorKind::TimedOut,
+                description: "timed out".to_owned(),
+            })
+        }));
+        let mut session = FastbootSession::new(mock);
+        session.set_cancel_flag(cancel.clone());
+        assert_eq!(Err(FastbootError::Cancelled), session.erase("userdata"));
+        assert_eq!(1, session.get_ref().read.calls().len());
+
+        // Nothing is sent once cancelled
+        session.get_ref().write.reset_calls();
+        assert_eq!(Err(FastbootError::Cancelled), session.reboot());
+        assert!(!session.get_ref().write.called());
+
+        cancel.store(false, Ordering::SeqCst);
+        session
+            .get_ref()
+            .read
+            .use_closure(reply_sequence(vec!["OKAY"]));
+        assert_eq!(Ok(()), session.reboot());
+
+        // Session methods that use the cache are cancelled too
+        cancel.store(true, Ordering::SeqCst);
+        session.get_ref().write.reset_calls();
+        assert_eq!(Err(FastbootError::Cancelled), session.download(b"data"));
+        assert_eq!(
+            Err(FastbootError::Cancelled),
+            session.flash_current_slot("boot", b"data")
+        );
+        assert!(!session.get_ref().write.called());
+
+        // Including a download that is already under way
+        cancel.store(false, Ordering::SeqCst);
+        let flag = cancel.clone();
+        session.get_ref().write.use_closure(Box::new(move |buf| {
+            if buf.starts_with(b"download:") {
+                flag.store(true, Ordering::SeqCst);
+            }
+            Ok(buf.len())
         }));
-        assert_eq!(Err("".to_owned()), mock.download(&vec![0; 1024]));
+        session.get_ref().read.use_closure(reply_sequence(vec![
+            "OKAY0x1000",
+            "DATA00000004",
+            "OKAY",
+        ]));
+        assert_eq!(Err(FastbootError::Cancelled), session.download(b"data"));
+        assert!(session
+            .get_ref()
+            .write
+            .called_with(b"download:00000004".to_vec()));
+        assert!(!session.get_ref().write.called_with(b"data".to_vec()));
+    }
+
+    #[test]
+    fn test_download_stream() {
+        let mut mock = MockUsb::default();
+
+        let data: Vec<u8> = (0..0x180000).map(|i| i as u8).collect();
+        mock.write.use_closure(Box::new(|buf| Ok(buf.len())));
+        mock.read.use_closure(reply_sequence(vec![
+            "OKAY0x01000000",
+            "DATA00180000",
+            "OKAY",
+        ]));
+        assert_eq!(
+            Ok(data.len() as u64),
+            mock.download_stream(io::Cursor::new(&data), data.len() as u64)
+                .map(|stats| stats.bytes)
+        );
+        let calls = mock.write.calls();
+        assert_eq!("download:00180000".as_bytes(), &calls[1][..]);
+        assert_eq!(
+            vec![0x100000, 0x80000],
+            calls[2..].iter().map(Vec::len).collect::<Vec<_>>()
+        );
+        assert_eq!(data, calls[2..].concat());
+
+        // The source running dry is reported instead of stalling the client
+        mock.read
+            .use_closure(reply_sequence(vec!["OKAY0x01000000", "DATA00000010"]));
+        match mock.download_stream(io::Cursor::new(vec![0; 8]), 16) {
+            Err(FastbootError::Io(_)) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_transfer_stats() {
+        let stats = TransferStats {
+            bytes: 3000,
+            elapsed: std::time::Duration::from_millis(1500),
+        };
+        assert_eq!(2000.0, stats.throughput());
+        let stats = TransferStats {
+            bytes: 3000,
+            elapsed: std::time::Duration::from_secs(0),
+        };
+        assert_eq!(0.0, stats.throughput());
+    }
+
+    #[test]
+    fn test_download_with_progress() {
+        let mut mock = MockUsb::default();
+
+        mock.write.use_closure(Box::new(|buf| Ok(buf.len())));
+        mock.read.use_closure(reply_sequence(vec![
+            "OKAY0x01000000",
+            "DATA00280000",
+            "OKAY",
+        ]));
+        let mut progress = Vec::new();
+        assert_eq!(
+            Ok(()),
+            mock.download_with_progress(&vec![0; 0x280000], &mut |sent, total| {
+                progress.push((sent, total))
+            })
+        );
+        assert_eq!(
+            vec![
+                (0x100000, 0x280000),
+                (0x200000, 0x280000),
+                (0x280000, 0x280000)
+            ],
+            progress
+        );
+    }
+
+    #[test]
+    fn test_download_throttled() {
+        let mut mock = MockUsb::default();
+
+        mock.write.use_closure(Box::new(|buf| Ok(buf.len())));
+        mock.read
+            .use_closure(reply_sequence(vec!["OKAY0x1000", "DATA0000000a", "OKAY"]));
+        let start = std::time::Instant::now();
+        assert_eq!(
+            Ok(()),
+            mock.download_throttled(
+                "0123456789".as_bytes(),
+                4,
+                std::time::Duration::from_millis(20)
+            )
+        );
+        // Two pauses, none after the last chunk
+        assert!(start.elapsed() >= std::time::Duration::from_millis(40));
+        let chunks: Vec<Vec<u8>> = mock.write.calls().into_iter().skip(2).collect();
+        assert_eq!(
+            vec![b"0123".to_vec(), b"4567".to_vec(), b"89".to_vec()],
+            chunks
+        );
+
+        match mock.download_throttled("data".as_bytes(), 0, std::time::Duration::from_millis(0)) {
+            Err(FastbootError::Protocol(_)) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_stage() {
+        let mut mock = MockUsb::default();
+
+        mock.write.use_closure(Box::new(|buf| Ok(buf.len())));
+        mock.read
+            .use_closure(reply_sequence(vec!["OKAY0x1000", "DATA00000004", "OKAY"]));
+        assert_eq!(Ok(()), mock.stage("keys".as_bytes()));
+        assert_eq!(
+            vec![
+                b"getvar:max-download-size".to_vec(),
+                b"download:00000004".to_vec(),
+                b"keys".to_vec(),
+            ],
+            mock.write.calls()
+        );
+    }
+
+    #[test]
+    fn test_upload() {
+        let mut mock = MockUsb::default();
+
+        mock.write.return_value_for("upload".as_bytes(), Ok(6));
+        mock.read
+            .use_closure(reply_sequence(vec!["DATA00000004", "data", "OKAY"]));
+        assert_eq!(Ok(b"data".to_vec()), mock.upload());
+
+        // Data that arrives in several reads
+        mock.read.use_closure(reply_sequence(vec![
+            "DATA0000000a",
+            "abc",
+            "def",
+            "ghij",
+            "OKAY",
+        ]));
+        assert_eq!(Ok(b"abcdefghij".to_vec()), mock.upload());
+
+        mock.read
+            .use_closure(reply_sequence(vec!["FAILnothing staged"]));
+        assert_eq!(
+            Err(FastbootError::Fail("nothing staged".to_owned())),
+            mock.upload()
+        );
+    }
+
+    #[test]
+    fn test_get_staged_to_writer() {
+        let mut mock = MockUsb::default();
+
+        mock.write.return_value_for("upload".as_bytes(), Ok(6));
+        mock.read.use_closure(reply_sequence(vec![
+            "DATA0000000b",
+            "staged ",
+            "data",
+            "OKAY",
+        ]));
+        let mut dest = Vec::new();
+        assert_eq!(Ok(11), mock.get_staged_to_writer(&mut dest));
+        assert_eq!(b"staged data".to_vec(), dest);
+
+        mock.read
+            .use_closure(reply_sequence(vec!["FAILnothing staged"]));
+        assert_eq!(
+            Err(FastbootError::Fail("nothing staged".to_owned())),
+            mock.get_staged_to_writer(Vec::new())
+        );
+    }
+
+    #[test]
+    fn test_fetch() {
+        let mut mock = MockUsb::default();
+
+        mock.write.use_closure(Box::new(|buf| Ok(buf.len())));
+        mock.read
+            .use_closure(reply_sequence(vec!["DATA00000004", "boot", "OKAY"]));
+        assert_eq!(Ok(b"boot".to_vec()), mock.fetch("boot", None, None));
+        assert!(mock.write.called_with("fetch:boot".as_bytes()));
+
+        mock.read
+            .use_closure(reply_sequence(vec!["DATA00000004", "boot", "OKAY"]));
+        assert_eq!(Ok(b"boot".to_vec()), mock.fetch("boot", Some(0x1000), None));
+        assert!(mock.write.called_with("fetch:boot:0x00001000".as_bytes()));
+
+        mock.read
+            .use_closure(reply_sequence(vec!["DATA00000004", "boot", "OKAY"]));
+        assert_eq!(
+            Ok(b"boot".to_vec()),
+            mock.fetch("boot", Some(0x1000), Some(4))
+        );
+        assert!(mock
+            .write
+            .called_with("fetch:boot:0x00001000:0x00000004".as_bytes()));
+    }
+
+    #[test]
+    fn test_partition_info() {
+        let mut mock = MockUsb::default();
+
+        mock.write
+            .return_value_for("getvar:partition-type:system".as_bytes(), Ok(28));
+        mock.read.use_closure(reply_sequence(vec!["OKAYext4"]));
+        assert_eq!(Ok("ext4".to_owned()), mock.partition_type("system"));
+
+        mock.write
+            .return_value_for("getvar:partition-size:boot".as_bytes(), Ok(26));
+        mock.read
+            .use_closure(reply_sequence(vec!["OKAY0x4000000", "OKAY1024", "OKAYbig"]));
+        assert_eq!(Ok(0x400_0000), mock.partition_size("boot"));
+        assert_eq!(Ok(1024), mock.partition_size("boot"));
+        match mock.partition_size("boot") {
+            Err(FastbootError::Protocol(_)) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+
+        mock.read
+            .use_closure(reply_sequence(vec!["FAILunknown partition"]));
+        assert_eq!(
+            Err(FastbootError::Fail("unknown partition".to_owned())),
+            mock.partition_size("boot")
+        );
+    }
+
+    #[test]
+    fn test_max_download_size() {
+        let mut mock = MockUsb::default();
+
+        mock.write
+            .return_value_for("getvar:max-download-size".as_bytes(), Ok(24));
+        mock.read
+            .use_closure(Box::new(|_| Ok("OKAY0x00000800".as_bytes().to_vec())));
+        assert_eq!(Ok(2048), mock.max_download_size());
+
+        mock.read
+            .use_closure(Box::new(|_| Ok("OKAY4096".as_bytes().to_vec())));
+        assert_eq!(Ok(4096), mock.max_download_size());
+
+        // Nothing is sent once the limit is known to be exceeded
+        mock.write.reset_calls();
+        match mock.download(&vec![0; 4097]) {
+            Err(FastbootError::Protocol(_)) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        assert!(!mock.write.called_with("download:00001001".as_bytes()));
     }
 
     #[test]
@@ -141,21 +989,96 @@ mod tests {
 
         mock.write
             .return_value_for("flash:mmc0:dead".as_bytes(), Ok(15));
-        mock.read.use_closure(Box::new(|buf| {
-            let reply = "OKAY1.0";
-            unsafe { reply.as_ptr().copy_to_nonoverlapping(buf, reply.len()) };
-            Ok(reply.len())
-        }));
+        mock.read
+            .use_closure(Box::new(|_| Ok("OKAY1.0".as_bytes().to_vec())));
         assert_eq!(Ok(()), mock.flash("mmc0:dead"));
 
         mock.write
             .return_value_for("flash:something".as_bytes(), Ok(15));
-        mock.read.use_closure(Box::new(|buf| {
-            let reply = "FAIL";
-            unsafe { reply.as_ptr().copy_to_nonoverlapping(buf, reply.len()) };
-            Ok(reply.len())
-        }));
-        assert_eq!(Err("".to_owned()), mock.flash("something"));
+        mock.read
+            .use_closure(Box::new(|_| Ok("FAIL".as_bytes().to_vec())));
+        assert_eq!(
+            Err(FastbootError::Fail("".to_owned())),
+            mock.flash("something")
+        );
+    }
+
+    #[test]
+    fn test_verbose() {
+        let mut mock = MockUsb::default();
+
+        mock.write.return_value_for("flash:boot".as_bytes(), Ok(10));
+        mock.read.use_closure(reply_sequence(vec![
+            "INFOwriting",
+            "OKAY4096 bytes in 0.1s",
+        ]));
+        assert_eq!(
+            Ok("4096 bytes in 0.1s".to_owned()),
+            mock.flash_verbose("boot")
+        );
+
+        mock.write.return_value_for("erase:boot".as_bytes(), Ok(10));
+        mock.read.use_closure(reply_sequence(vec!["OKAY"]));
+        assert_eq!(Ok("".to_owned()), mock.erase_verbose("boot"));
+        mock.read.use_closure(reply_sequence(vec!["FAILbusy"]));
+        assert_eq!(
+            Err(FastbootError::Fail("busy".to_owned())),
+            mock.erase_verbose("boot")
+        );
+    }
+
+    #[test]
+    fn test_flash_current_slot() {
+        let mut mock = MockUsb::default();
+
+        mock.write.use_closure(Box::new(|buf| Ok(buf.len())));
+        mock.read.use_closure(reply_sequence(vec![
+            "OKAYb",
+            "OKAY0x1000",
+            "DATA00000004",
+            "OKAY",
+        ]));
+        assert_eq!(Ok(()), mock.flash_current_slot("boot", "data".as_bytes()));
+        assert!(mock.write.called_with("flash:boot_b".as_bytes()));
+
+        // Some clients report the slot with its underscore
+        mock.read.use_closure(reply_sequence(vec![
+            "OKAY_a",
+            "OKAY0x1000",
+            "DATA00000004",
+            "OKAY",
+        ]));
+        assert_eq!(Ok(()), mock.flash_current_slot("boot", "data".as_bytes()));
+        assert!(mock.write.called_with("flash:boot_a".as_bytes()));
+
+        // Not an A/B device
+        mock.write.reset_calls();
+        mock.read.use_closure(reply_sequence(vec![
+            "FAILunknown variable",
+            "OKAY0x1000",
+            "DATA00000004",
+            "OKAY",
+        ]));
+        assert_eq!(Ok(()), mock.flash_current_slot("boot", "data".as_bytes()));
+        assert!(mock.write.called_with("flash:boot".as_bytes()));
+    }
+
+    #[test]
+    fn test_flash_with_progress() {
+        let mut mock = MockUsb::default();
+
+        mock.write.return_value_for("flash:boot".as_bytes(), Ok(10));
+        mock.read.use_closure(reply_sequence(vec![
+            "INFOerasing flash",
+            "INFOwriting flash",
+            "OKAY",
+        ]));
+        let mut lines = Vec::new();
+        assert_eq!(
+            Ok(()),
+            mock.flash_with_progress("boot", &mut |line| lines.push(line.to_owned()))
+        );
+        assert_eq!(vec!["erasing flash", "writing flash"], lines);
     }
 
     #[test]
@@ -164,33 +1087,1548 @@ mod tests {
 
         mock.write
             .return_value_for("erase:mmc0:dead".as_bytes(), Ok(15));
-        mock.read.use_closure(Box::new(|buf| {
-            let reply = "OKAY1.0";
-            unsafe { reply.as_ptr().copy_to_nonoverlapping(buf, reply.len()) };
-            Ok(reply.len())
-        }));
+        mock.read
+            .use_closure(Box::new(|_| Ok("OKAY1.0".as_bytes().to_vec())));
         assert_eq!(Ok(()), mock.erase("mmc0:dead"));
 
         mock.write
             .return_value_for("erase:something".as_bytes(), Ok(15));
-        mock.read.use_closure(Box::new(|buf| {
-            let reply = "FAIL";
-            unsafe { reply.as_ptr().copy_to_nonoverlapping(buf, reply.len()) };
-            Ok(reply.len())
-        }));
-        assert_eq!(Err("".to_owned()), mock.erase("something"));
+        mock.read
+            .use_closure(Box::new(|_| Ok("FAIL".as_bytes().to_vec())));
+        assert_eq!(
+            Err(FastbootError::Fail("".to_owned())),
+            mock.erase("something")
+        );
+
+        // Slow storage reports progress before finishing, nothing is read
+        // past the final reply
+        mock.read.reset_calls();
+        mock.read.use_closure(reply_sequence(vec![
+            "INFOerasing 50%",
+            "TEXTerasing 100%",
+            "OKAY",
+        ]));
+        let mut lines = Vec::new();
+        assert_eq!(
+            Ok(()),
+            mock.erase_with_progress("something", &mut |line| lines.push(line.to_owned()))
+        );
+        assert_eq!(vec!["erasing 50%", "erasing 100%"], lines);
+        assert_eq!(3, mock.read.calls().len());
+
+        mock.write.return_value_for("reboot".as_bytes(), Ok(6));
+        mock.read
+            .use_closure(reply_sequence(vec!["INFOrebooting", "OKAY"]));
+        assert_eq!(Ok(()), mock.reboot());
     }
 
     #[test]
-    fn test_reboot() {
+    fn test_download_and_flash() {
         let mut mock = MockUsb::default();
 
-        mock.write.return_value_for("reboot".as_bytes(), Ok(6));
-        mock.read.use_closure(Box::new(|buf| {
-            let reply = "OKAY1.0";
-            unsafe { reply.as_ptr().copy_to_nonoverlapping(buf, reply.len()) };
-            Ok(reply.len())
-        }));
-        assert_eq!(Ok(()), mock.reboot());
+        mock.write.use_closure(Box::new(|buf| Ok(buf.len())));
+        mock.read
+            .use_closure(reply_sequence(vec!["OKAY0x1000", "DATA00000004", "OKAY"]));
+        assert_eq!(Ok(()), mock.download_and_flash("boot_a", "data".as_bytes()));
+        assert_eq!(Ok(()), mock.flash_staged("boot_b"));
+        assert!(mock.write.called_with("download:00000004".as_bytes()));
+        assert!(mock.write.called_with("flash:boot_a".as_bytes()));
+        assert!(mock.write.called_with("flash:boot_b".as_bytes()));
+
+        // Nothing is downloaded for an invalid name
+        mock.write.reset_calls();
+        assert!(mock.download_and_flash("", "data".as_bytes()).is_err());
+        assert!(!mock.write.called());
+    }
+
+    #[test]
+    fn test_flash_at() {
+        let mut mock = MockUsb::default();
+
+        mock.write.use_closure(Box::new(|buf| Ok(buf.len())));
+        mock.read
+            .use_closure(reply_sequence(vec!["OKAY0x1000", "DATA00000004", "OKAY"]));
+        assert_eq!(
+            Ok(()),
+            mock.flash_at("bootloader", 0x200, "data".as_bytes())
+        );
+        assert!(mock
+            .write
+            .called_with("flash:bootloader:0x00000200".as_bytes()));
+    }
+
+    #[test]
+    fn test_flash_preflight() {
+        let mut mock = MockUsb::default();
+
+        mock.write.use_closure(Box::new(|buf| Ok(buf.len())));
+        mock.read
+            .use_closure(reply_sequence(vec!["OKAY0x1000", "OKAYyes"]));
+        let report = mock.flash_preflight("boot", 0x1000).unwrap();
+        assert!(report.is_ok());
+        assert_eq!(PreflightReport::default(), report);
+        assert!(mock
+            .write
+            .called_with("getvar:partition-size:boot".as_bytes()));
+
+        mock.read
+            .use_closure(reply_sequence(vec!["OKAY0x1000", "OKAYno"]));
+        assert_eq!(
+            Ok(PreflightReport {
+                errors: vec![PreflightIssue::TooBig(0x1000), PreflightIssue::Locked],
+                warnings: vec![],
+            }),
+            mock.flash_preflight("boot", 0x1001)
+        );
+
+        mock.read.use_closure(reply_sequence(vec![
+            "FAILunknown partition",
+            "FAILunknown variable",
+        ]));
+        let report = mock.flash_preflight("bot", 0x1000).unwrap();
+        assert!(!report.is_ok());
+        assert_eq!(vec![PreflightIssue::UnknownPartition], report.errors);
+        assert_eq!(
+            vec![PreflightIssue::Unchecked("unlocked".to_owned())],
+            report.warnings
+        );
+    }
+
+    #[test]
+    fn test_run_sequence() {
+        let mut mock = MockUsb::default();
+
+        let steps = vec![
+            Step::Erase("boot".to_owned()),
+            Step::Download(b"data".to_vec()),
+            Step::Flash("boot".to_owned()),
+            Step::SetActive("b".to_owned()),
+            Step::Reboot,
+        ];
+        mock.write.use_closure(Box::new(|buf| Ok(buf.len())));
+        mock.read.use_closure(reply_sequence(vec![
+            "OKAY",
+            "OKAY0x1000",
+            "DATA00000004",
+            "OKAY",
+        ]));
+        assert_eq!(Ok(vec![StepResult::Done; 5]), mock.run_sequence(&steps));
+
+        // A failed flash stops everything after it
+        mock.write.reset_calls();
+        mock.read.use_closure(reply_sequence(vec![
+            "OKAY",
+            "OKAY0x1000",
+            "DATA00000004",
+            "OKAY",
+            "FAILimage too large",
+        ]));
+        assert_eq!(
+            Ok(vec![
+                StepResult::Done,
+                StepResult::Done,
+                StepResult::Failed(FastbootError::Fail("image too large".to_owned())),
+                StepResult::Skipped,
+                StepResult::Skipped,
+            ]),
+            mock.run_sequence(&steps)
+        );
+        assert!(!mock.write.called_with("set_active:b".as_bytes()));
+        assert!(!mock.write.called_with("reboot".as_bytes()));
+
+        // So does a client that went away, only with nothing to tell
+        mock.write.reset_calls();
+        mock.read.use_closure(Box::new(|_| {
+            Err(CloneableError {
+                kind: io::ErrorKind::NotConnected,
+                description: "no device".to_owned(),
+            })
+        }));
+        assert_eq!(
+            Err(FastbootError::DeviceDisconnected),
+            mock.run_sequence(&steps)
+        );
+        assert_eq!(1, mock.write.num_calls());
+    }
+
+    #[test]
+    fn test_invalid_partition() {
+        let mut mock = MockUsb::default();
+
+        mock.write.use_closure(Box::new(|buf| Ok(buf.len())));
+        mock.read.use_closure(reply_sequence(vec!["OKAY"]));
+        for partition in &["", "boot\nerase:userdata", "boot\0"] {
+            match mock.erase(partition) {
+                Err(FastbootError::Protocol(_)) => (),
+                result => panic!("Unexpected result: {:?}", result),
+            }
+            match mock.flash(partition) {
+                Err(FastbootError::Protocol(_)) => (),
+                result => panic!("Unexpected result: {:?}", result),
+            }
+            match mock.flash_current_slot(partition, "data".as_bytes()) {
+                Err(FastbootError::Protocol(_)) => (),
+                result => panic!("Unexpected result: {:?}", result),
+            }
+            match mock.fetch(partition, None, None) {
+                Err(FastbootError::Protocol(_)) => (),
+                result => panic!("Unexpected result: {:?}", result),
+            }
+        }
+        assert!(!mock.write.called());
+    }
+
+    #[test]
+    fn test_reboot() {
+        let mut mock = MockUsb::default();
+
+        mock.write.return_value_for("reboot".as_bytes(), Ok(6));
+        mock.read
+            .use_closure(Box::new(|_| Ok("OKAY1.0".as_bytes().to_vec())));
+        assert_eq!(Ok(()), mock.reboot());
+    }
+
+    #[test]
+    fn test_boot() {
+        let mut mock = MockUsb::default();
+
+        mock.write.return_value_for("boot".as_bytes(), Ok(4));
+        mock.read
+            .use_closure(Box::new(|_| Ok("OKAY".as_bytes().to_vec())));
+        assert_eq!(Ok(()), mock.boot());
+
+        mock.read
+            .use_closure(reply_sequence(vec!["INFOloading", "INFOstarting", "OKAY"]));
+        assert_eq!(Ok(()), mock.boot());
+
+        mock.read
+            .use_closure(Box::new(|_| Ok("FAILno image".as_bytes().to_vec())));
+        assert_eq!(Err(FastbootError::Fail("no image".to_owned())), mock.boot());
+    }
+
+    #[test]
+    fn test_continue_boot() {
+        let mut mock = MockUsb::default();
+
+        mock.write.return_value_for("continue".as_bytes(), Ok(8));
+        mock.read
+            .use_closure(Box::new(|_| Ok("OKAY".as_bytes().to_vec())));
+        assert_eq!(Ok(()), mock.continue_boot());
+        assert_eq!(1, mock.read.num_calls());
+    }
+
+    #[test]
+    fn test_reboot_bootloader() {
+        let mut mock = MockUsb::default();
+
+        mock.write
+            .return_value_for("reboot-bootloader".as_bytes(), Ok(17));
+        mock.read
+            .use_closure(Box::new(|_| Ok("OKAY".as_bytes().to_vec())));
+        assert_eq!(Ok(()), mock.reboot_bootloader());
+        assert!(mock.write.called_with("reboot-bootloader".as_bytes()));
+    }
+
+    #[test]
+    fn test_reboot_fastboot() {
+        let mut mock = MockUsb::default();
+
+        mock.write
+            .return_value_for("reboot-fastboot".as_bytes(), Ok(15));
+        mock.read.use_closure(reply_sequence(vec!["OKAY"]));
+        assert_eq!(Ok(()), mock.reboot_fastboot());
+        assert_eq!(
+            vec!["reboot-fastboot".as_bytes().to_vec()],
+            mock.write.calls()
+        );
+    }
+
+    #[test]
+    fn test_reboot_recovery() {
+        let mut mock = MockUsb::default();
+
+        mock.write
+            .return_value_for("reboot-recovery".as_bytes(), Ok(15));
+        mock.read
+            .use_closure(Box::new(|_| Ok("OKAY".as_bytes().to_vec())));
+        assert_eq!(Ok(()), mock.reboot_recovery());
+        assert!(mock.write.called_with("reboot-recovery".as_bytes()));
+    }
+
+    #[test]
+    fn test_signature() {
+        let mut mock = MockUsb::default();
+
+        mock.write.use_closure(Box::new(|buf| Ok(buf.len())));
+        mock.read.use_closure(reply_sequence(vec![
+            "OKAY0x1000",
+            "DATA00000003",
+            "OKAY",
+            "OKAY",
+        ]));
+        assert_eq!(Ok(()), mock.send_signature(b"sig"));
+        let calls = mock.write.calls();
+        assert_eq!(
+            vec![
+                b"getvar:max-download-size".to_vec(),
+                b"download:00000003".to_vec(),
+                b"sig".to_vec(),
+                b"signature".to_vec(),
+            ],
+            calls
+        );
+
+        mock.write
+            .return_value_for("verify:boot".as_bytes(), Ok(11));
+        mock.read
+            .use_closure(reply_sequence(vec!["FAILsignature mismatch"]));
+        assert_eq!(
+            Err(FastbootError::Fail("signature mismatch".to_owned())),
+            mock.verify("boot")
+        );
+        assert!(mock.write.called_with("verify:boot".as_bytes()));
+    }
+
+    #[test]
+    fn test_powerdown() {
+        let mut mock = MockUsb::default();
+
+        mock.write.return_value_for("powerdown".as_bytes(), Ok(9));
+        mock.read.use_closure(reply_sequence(vec!["OKAY"]));
+        assert_eq!(Ok(()), mock.powerdown());
+
+        mock.read
+            .use_closure(reply_sequence(vec!["FAILunknown command"]));
+        let err = mock.powerdown().unwrap_err();
+        assert!(err.is_unknown_command());
+        assert!(!FastbootError::Fail("locked".to_owned()).is_unknown_command());
+        assert!(!FastbootError::Timeout.is_unknown_command());
+    }
+
+    #[test]
+    fn test_set_active() {
+        let mut mock = MockUsb::default();
+
+        mock.write
+            .return_value_for("set_active:a".as_bytes(), Ok(12));
+        mock.read
+            .use_closure(Box::new(|_| Ok("OKAY".as_bytes().to_vec())));
+        assert_eq!(Ok(()), mock.set_active("a"));
+
+        mock.write.reset_calls();
+        assert!(mock.set_active("").is_err());
+        assert!(!mock.write.called());
+    }
+
+    #[test]
+    fn test_oem() {
+        let mut mock = MockUsb::default();
+
+        mock.write
+            .return_value_for("oem device-info".as_bytes(), Ok(15));
+        mock.read.use_closure(reply_sequence(vec![
+            "INFOunlocked: no",
+            "INFOcharger: yes",
+            "OKAY",
+        ]));
+        assert_eq!(
+            Ok("unlocked: no\ncharger: yes".to_owned()),
+            mock.oem("device-info")
+        );
+
+        mock.write
+            .return_value_for("oem unknown".as_bytes(), Ok(11));
+        mock.read
+            .use_closure(Box::new(|_| Ok("FAILunknown command".as_bytes().to_vec())));
+        assert_eq!(
+            Err(FastbootError::Fail("unknown command".to_owned())),
+            mock.oem("unknown")
+        );
+    }
+
+    #[test]
+    fn test_command_lines() {
+        let mut mock = MockUsb::default();
+
+        mock.write
+            .return_value_for("oem dump-logs".as_bytes(), Ok(13));
+        mock.read
+            .use_closure(reply_sequence(vec!["INFOboot", "TEXTkernel", "OKAY"]));
+        let lines: Vec<_> = mock.command_lines("oem dump-logs").collect();
+        assert_eq!(
+            vec![
+                Ok(Line::Info(b"boot".to_vec())),
+                Ok(Line::Text(b"kernel".to_vec()))
+            ],
+            lines
+        );
+
+        mock.read
+            .use_closure(reply_sequence(vec!["INFOboot", "FAILno logs"]));
+        let lines: Vec<_> = mock.command_lines("oem dump-logs").collect();
+        assert_eq!(
+            vec![
+                Ok(Line::Info(b"boot".to_vec())),
+                Err(FastbootError::Fail("no logs".to_owned()))
+            ],
+            lines
+        );
+
+        // Replies left behind are read when dropped
+        mock.read.reset_calls();
+        mock.read
+            .use_closure(reply_sequence(vec!["INFOboot", "INFOkernel", "OKAY"]));
+        assert_eq!(
+            Some(Ok(Line::Info(b"boot".to_vec()))),
+            mock.command_lines("oem dump-logs").next()
+        );
+        assert_eq!(3, mock.read.num_calls());
+
+        // Binary payloads are kept as they are
+        let calls = Cell::new(0);
+        mock.read.use_closure(Box::new(move |_| {
+            calls.set(calls.get() + 1);
+            match calls.get() {
+                1 => Ok(b"INFO\xde\xad\xbe\xef".to_vec()),
+                _ => Ok(b"OKAY".to_vec()),
+            }
+        }));
+        let lines: Vec<_> = mock.command_lines("oem dump-logs").collect();
+        assert_eq!(vec![Ok(Line::Info(vec![0xde, 0xad, 0xbe, 0xef]))], lines);
+        assert_eq!(
+            "a\u{fffd}\u{fffd}",
+            Line::Text(vec![b'a', 0xff, 0xfe]).to_string_lossy()
+        );
+    }
+
+    #[test]
+    fn test_capture_log() {
+        let mut mock = MockUsb::default();
+
+        mock.write
+            .return_value_for("oem dump-logs".as_bytes(), Ok(13));
+        mock.read.use_closure(reply_sequence(vec![
+            "INFO[0.000] boot",
+            "TEXT[0.100] kernel",
+            "INFO[0.200] done",
+            "OKAY",
+        ]));
+        let mut log = Vec::new();
+        assert_eq!(Ok(()), mock.capture_log("dump-logs", &mut log));
+        assert_eq!(
+            "[0.000] boot\n[0.100] kernel\n[0.200] done\n",
+            String::from_utf8(log).unwrap()
+        );
+
+        // What's received before a failure is kept
+        mock.read
+            .use_closure(reply_sequence(vec!["INFO[0.000] boot", "FAILno more"]));
+        let mut log = Vec::new();
+        assert_eq!(
+            Err(FastbootError::Fail("no more".to_owned())),
+            mock.capture_log("dump-logs", &mut log)
+        );
+        assert_eq!(b"[0.000] boot\n".to_vec(), log);
+    }
+
+    #[test]
+    fn test_oem_with_data() {
+        let mut mock = MockUsb::default();
+
+        mock.write.use_closure(Box::new(|buf| Ok(buf.len())));
+        mock.read.use_closure(reply_sequence(vec![
+            "OKAY0x1000",
+            "DATA00000004",
+            "OKAY",
+            "INFOkey stored",
+            "OKAY",
+        ]));
+        assert_eq!(
+            Ok("key stored".to_owned()),
+            mock.oem_with_data("provision-key", "data".as_bytes())
+        );
+        assert_eq!(
+            vec![
+                "getvar:max-download-size".as_bytes().to_vec(),
+                "download:00000004".as_bytes().to_vec(),
+                "data".as_bytes().to_vec(),
+                "oem provision-key".as_bytes().to_vec(),
+            ],
+            mock.write.calls()
+        );
+
+        // The command isn't run if the data is refused
+        mock.write.reset_calls();
+        mock.read
+            .use_closure(reply_sequence(vec!["OKAY0x1000", "FAILno space"]));
+        assert_eq!(
+            Err(FastbootError::Fail("no space".to_owned())),
+            mock.oem_with_data("provision-key", "data".as_bytes())
+        );
+        assert!(!mock.write.called_with("oem provision-key".as_bytes()));
+    }
+
+    #[test]
+    fn test_flashing() {
+        let mut mock = MockUsb::default();
+
+        mock.write
+            .return_value_for("flashing unlock".as_bytes(), Ok(15));
+        mock.read.use_closure(reply_sequence(vec![
+            "INFOConfirm on the device",
+            "INFOWaiting for confirmation",
+            "OKAY",
+        ]));
+        assert_eq!(Ok(()), mock.flashing_unlock());
+
+        mock.write
+            .return_value_for("flashing lock".as_bytes(), Ok(13));
+        mock.read.use_closure(reply_sequence(vec![
+            "INFOConfirm on the device",
+            "FAILDenied",
+        ]));
+        assert_eq!(
+            Err(FastbootError::Fail("Denied".to_owned())),
+            mock.flashing_lock()
+        );
+
+        mock.write
+            .return_value_for("flashing get_unlock_ability".as_bytes(), Ok(27));
+        mock.read
+            .use_closure(reply_sequence(vec!["INFOget_unlock_ability: 1", "OKAY"]));
+        assert_eq!(Ok(true), mock.flashing_get_unlock_ability());
+        mock.read.use_closure(reply_sequence(vec!["OKAY0"]));
+        assert_eq!(Ok(false), mock.flashing_get_unlock_ability());
+        mock.read.use_closure(reply_sequence(vec!["OKAY"]));
+        match mock.flashing_get_unlock_ability() {
+            Err(FastbootError::Protocol(_)) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_run_command() {
+        let mut mock = MockUsb::default();
+
+        mock.write
+            .return_value_for("oem device-info".as_bytes(), Ok(15));
+        mock.read.use_closure(reply_sequence(vec![
+            "INFOunlocked: no",
+            "TEXTcharger: yes",
+            "OKAYdone",
+        ]));
+        let outcome = fastboot::run_command(&mut mock, b"oem device-info").unwrap();
+        assert_eq!(
+            fastboot::CommandOutcome {
+                info: vec!["unlocked: no".to_owned(), "charger: yes".to_owned()],
+                okay_payload: "done".to_owned(),
+            },
+            outcome
+        );
+        assert_eq!("unlocked: no\ncharger: yes\ndone", outcome.to_string());
+        assert_eq!("", fastboot::CommandOutcome::default().to_string());
+
+        mock.read
+            .use_closure(reply_sequence(vec!["INFOchecking", "FAILlocked"]));
+        assert_eq!(
+            Err(FastbootError::Fail("locked".to_owned())),
+            fastboot::run_command(&mut mock, b"oem device-info")
+        );
+    }
+
+    #[test]
+    fn test_fail_reason() {
+        use crate::fastboot::FailReason;
+
+        let cases = [
+            ("unknown command", FailReason::UnknownCommand),
+            ("not supported in locked state", FailReason::DeviceLocked),
+            (
+                "Flashing is not allowed in Lock State",
+                FailReason::DeviceLocked,
+            ),
+            ("Permission denied", FailReason::PermissionDenied),
+            ("Partition not found", FailReason::PartitionNotFound),
+            ("unknown partition", FailReason::PartitionNotFound),
+            ("Device is locked", FailReason::DeviceLocked),
+            ("Device already unlocked", FailReason::Other),
+            ("write failed", FailReason::Other),
+        ];
+        for (message, reason) in cases.iter() {
+            assert_eq!(*reason, FailReason::classify(message), "{}", message);
+        }
+
+        let mut mock = MockUsb::default();
+        mock.write.return_value_for("erase:boot".as_bytes(), Ok(10));
+        mock.read
+            .use_closure(reply_sequence(vec!["FAILnot supported in locked state"]));
+        let err = mock.erase("boot").unwrap_err();
+        assert_eq!(Some(FailReason::DeviceLocked), err.fail_reason());
+        assert_eq!(
+            FastbootError::Fail("not supported in locked state".to_owned()),
+            err
+        );
+        assert_eq!(None, FastbootError::Timeout.fail_reason());
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut mock = MockUsb::default();
+
+        mock.write.return_value(Err(CloneableError {
+            kind: io::ErrorKind::TimedOut,
+            description: "timed out".to_owned(),
+        }));
+        assert_eq!(Err(FastbootError::Timeout), mock.reboot());
+
+        mock.write.return_value(Err(CloneableError {
+            kind: io::ErrorKind::BrokenPipe,
+            description: "pipe".to_owned(),
+        }));
+        assert_eq!(Err(FastbootError::Io("pipe".to_owned())), mock.reboot());
+
+        mock.write.return_value(Ok(6));
+        mock.read
+            .use_closure(Box::new(|_| Ok("DATA00000004".as_bytes().to_vec())));
+        match mock.reboot() {
+            Err(FastbootError::Protocol(_)) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_short_reply() {
+        let mut mock = MockUsb::default();
+
+        mock.write.return_value_for("reboot".as_bytes(), Ok(6));
+        mock.read
+            .use_closure(Box::new(|_| Ok("OK".as_bytes().to_vec())));
+        assert!(mock.reboot().is_err());
+    }
+
+    #[test]
+    fn test_unknown_reply() {
+        let mut reply = *b"WHATever";
+        assert_eq!(
+            Reply::Unknown(b"WHATever".to_vec()),
+            Reply::from(&mut reply[..])
+        );
+
+        let mut mock = MockUsb::default();
+        mock.write.return_value_for("reboot".as_bytes(), Ok(6));
+        mock.read.use_closure(reply_sequence(vec!["WHATever"]));
+        match mock.reboot() {
+            Err(FastbootError::Protocol(_)) => (),
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_ping() {
+        let mut mock = MockUsb::default();
+
+        mock.write
+            .return_value_for("getvar:version".as_bytes(), Ok(14));
+        mock.read
+            .use_closure(reply_sequence(vec!["OKAY0.4", "FAILbusy"]));
+        assert_eq!(Ok(()), mock.ping());
+        assert_eq!(Err(FastbootError::Fail("busy".to_owned())), mock.ping());
+        assert!(mock.write.called_with("getvar:version".as_bytes()));
+    }
+
+    #[test]
+    fn test_raw_command() {
+        let mut mock = MockUsb::default();
+
+        mock.write.return_value_for("oem secret".as_bytes(), Ok(10));
+        mock.read
+            .use_closure(reply_sequence(vec!["FAILnot here", "INFOa\0b", "OK"]));
+        assert_eq!(
+            Ok(("FAIL".to_owned(), b"not here".to_vec())),
+            mock.raw_command(b"oem secret")
+        );
+        assert_eq!(
+            Ok(("INFO".to_owned(), b"a\0b".to_vec())),
+            mock.raw_command(b"oem secret")
+        );
+        assert_eq!(
+            Ok(("OK".to_owned(), Vec::new())),
+            mock.raw_command(b"oem secret")
+        );
+
+        mock.read
+            .use_closure(Box::new(|_| Ok(b"TEXT\xde\xad".to_vec())));
+        assert_eq!(
+            Ok(("TEXT".to_owned(), vec![0xde, 0xad])),
+            mock.raw_command(b"oem secret")
+        );
+    }
+
+    #[test]
+    fn test_resync() {
+        let mut mock = MockUsb::default();
+
+        // Garbage, then a stale reply, then the reply to the retried command
+        let calls = Cell::new(0);
+        mock.write.use_closure(Box::new(|buf| Ok(buf.len())));
+        mock.read.use_closure(Box::new(move |_| {
+            calls.set(calls.get() + 1);
+            let reply = match calls.get() {
+                1 => "\x01\x02garbage",
+                2 => "OKAYstale",
+                3 => {
+                    return Err(CloneableError {
+                        kind: io::ErrorKind::TimedOut,
+                        description: "timed out".to_owned(),
+                    })
+                }
+                _ => "OKAY0.4",
+            };
+            Ok(reply.as_bytes().to_vec())
+        }));
+        assert_eq!(
+            Ok(Reply::OKAY("0.4".to_owned())),
+            fastboot::send_command_with_resync(&mut mock, b"getvar:version")
+        );
+        assert_eq!(2, mock.write.num_calls());
+
+        // Replies that are fine aren't retried
+        mock.write.reset_calls();
+        mock.read.use_closure(reply_sequence(vec!["OKAY0.4"]));
+        assert_eq!(
+            Ok(Reply::OKAY("0.4".to_owned())),
+            fastboot::send_command_with_resync(&mut mock, b"getvar:version")
+        );
+        assert_eq!(1, mock.write.num_calls());
+
+        // Nor does it wait for a client that never stops sending
+        mock.write.reset_calls();
+        mock.read
+            .use_closure(Box::new(|_| Ok("WHATever".as_bytes().to_vec())));
+        assert!(fastboot::send_command_with_resync(&mut mock, b"getvar:version").is_err());
+        assert_eq!(1, mock.write.num_calls());
+    }
+
+    #[test]
+    fn test_protocol() {
+        assert_eq!(
+            Ok(RawReply::Okay(&b"0x1000"[..])),
+            protocol::decode_reply(b"OKAY0x1000")
+        );
+        assert_eq!(
+            Ok(RawReply::Data(0x400)),
+            protocol::decode_reply(b"DATA00000400")
+        );
+        assert_eq!(
+            Err(DecodeError::InvalidDataSize),
+            protocol::decode_reply(b"DATAzz")
+        );
+        assert_eq!(
+            Ok(RawReply::Okay(&b"sailfish"[..])),
+            protocol::decode_reply(b"OKAYsailfish\0\0OKAY0x1000")
+        );
+        assert_eq!(Err(DecodeError::Truncated), protocol::decode_reply(b"OK"));
+        assert_eq!(
+            Err(DecodeError::UnknownPrefix),
+            protocol::decode_reply(b"WHAT")
+        );
+
+        let mut buf = [0; 16];
+        assert_eq!(
+            Some(14),
+            protocol::encode_command(&mut buf, b"getvar:", b"product")
+        );
+        assert_eq!(b"getvar:product", &buf[..14]);
+        assert_eq!(
+            None,
+            protocol::encode_command(&mut buf, b"flash:", b"vendor_boot_a")
+        );
+
+        let mut buf = [0; protocol::DOWNLOAD_COMMAND_LEN];
+        assert_eq!(
+            Some(protocol::DOWNLOAD_COMMAND_LEN),
+            protocol::encode_download(&mut buf, 0x1f00)
+        );
+        assert_eq!(b"download:00001f00", &buf);
+        assert_eq!(None, protocol::encode_download(&mut buf, 1 << 32));
+    }
+
+    // Sparse image with 8-byte blocks: 2 RAW, 3 FILL, 1 DONT_CARE and a CRC32
+    fn sparse_image() -> Vec<u8> {
+        let mut image = Vec::new();
+        let mut push = |bytes: &[u8]| image.extend_from_slice(bytes);
+        // Header
+        push(&0xed26_ff3au32.to_le_bytes());
+        push(&[1, 0, 0, 0, 28, 0, 12, 0]);
+        push(&8u32.to_le_bytes());
+        push(&6u32.to_le_bytes());
+        push(&4u32.to_le_bytes());
+        push(&0u32.to_le_bytes());
+        // RAW
+        push(&[0xc1, 0xca, 0, 0, 2, 0, 0, 0, 28, 0, 0, 0]);
+        push(b"rawdata!RAWDATA!");
+        // FILL
+        push(&[0xc2, 0xca, 0, 0, 3, 0, 0, 0, 16, 0, 0, 0]);
+        push(&0xdead_beefu32.to_le_bytes());
+        // DONT_CARE
+        push(&[0xc3, 0xca, 0, 0, 1, 0, 0, 0, 12, 0, 0, 0]);
+        // CRC32
+        push(&[0xc4, 0xca, 0, 0, 0, 0, 0, 0, 16, 0, 0, 0]);
+        push(&0u32.to_le_bytes());
+        image
+    }
+
+    #[test]
+    fn test_sparse_parse() {
+        let image = sparse_image();
+        assert!(sparse::is_sparse(&image));
+        assert!(!sparse::is_sparse(b"ANDROID!"));
+
+        let parsed = SparseImage::parse(&image).unwrap();
+        assert_eq!(8, parsed.block_size);
+        assert_eq!(6, parsed.total_blocks);
+        let chunks: Vec<_> = parsed
+            .chunks
+            .iter()
+            .map(|chunk| (chunk.blocks, chunk.data.clone()))
+            .collect();
+        assert_eq!(
+            vec![
+                (2, ChunkData::Raw(b"rawdata!RAWDATA!")),
+                (3, ChunkData::Fill(0xdead_beef)),
+                (1, ChunkData::DontCare),
+                (0, ChunkData::Crc32(0)),
+            ],
+            chunks
+        );
+
+        assert!(SparseImage::parse(&image[..40]).is_err());
+    }
+
+    #[test]
+    fn test_sparse_split() {
+        let image = sparse_image();
+        let parsed = SparseImage::parse(&image).unwrap();
+
+        // Fits a single chunk with one block per sub-image
+        let split = parsed.split(72).unwrap();
+        assert_eq!(4, split.len());
+        let mut raw = Vec::new();
+        for sub_image in &split {
+            assert!(sub_image.len() <= 72);
+            let sub_image = SparseImage::parse(sub_image).unwrap();
+            assert_eq!(6, sub_image.total_blocks);
+            for chunk in sub_image.chunks {
+                if let ChunkData::Raw(data) = chunk.data {
+                    raw.extend_from_slice(data);
+                }
+            }
+        }
+        assert_eq!(b"rawdata!RAWDATA!".to_vec(), raw);
+
+        assert_eq!(1, parsed.split(1024).unwrap().len());
+        assert!(parsed.split(64).is_err());
+    }
+
+    #[test]
+    fn test_flash_sparse() {
+        let mut mock = MockUsb::default();
+
+        mock.write.use_closure(Box::new(|buf| Ok(buf.len())));
+        mock.read
+            .use_closure(reply_sequence(vec!["OKAY0x1000", "DATA00000054", "OKAY"]));
+        assert_eq!(Ok(()), mock.flash_sparse("system", &sparse_image()));
+        assert!(mock.write.called_with("download:00000054".as_bytes()));
+        assert!(mock.write.called_with("flash:system".as_bytes()));
+    }
+
+    #[test]
+    fn test_sparse_from_raw() {
+        let mut data = b"rawdata!".to_vec();
+        data.extend_from_slice(&[0; 16]);
+        data.extend_from_slice(b"RAWDATA!abababab");
+        let image = SparseImage::from_raw(8, &data).unwrap();
+        assert_eq!(5, image.total_blocks);
+        let chunks: Vec<_> = image
+            .chunks
+            .iter()
+            .map(|chunk| (chunk.blocks, chunk.data.clone()))
+            .collect();
+        assert_eq!(
+            vec![
+                (1, ChunkData::Raw(b"rawdata!")),
+                (2, ChunkData::Fill(0)),
+                (1, ChunkData::Raw(b"RAWDATA!")),
+                (1, ChunkData::Fill(0x6261_6261)),
+            ],
+            chunks
+        );
+
+        assert!(SparseImage::from_raw(8, &data[..12]).is_err());
+    }
+
+    #[test]
+    fn test_flash_auto() {
+        let max_size = 0x10000;
+        let data: Vec<u8> = (0..3 * max_size).map(|i| (i % 251) as u8).collect();
+        let sub_images = SparseImage::from_raw(sparse::DEFAULT_BLOCK_SIZE, &data)
+            .unwrap()
+            .split(max_size)
+            .unwrap();
+        assert_eq!(4, sub_images.len());
+
+        let mut replies = vec![format!("OKAY{:#x}", max_size)];
+        for sub_image in &sub_images {
+            replies.push(format!("DATA{:08x}", sub_image.len()));
+            replies.push("OKAY".to_owned());
+            replies.push("OKAY".to_owned());
+        }
+        let index = Cell::new(0);
+        let mut mock = MockUsb::default();
+        mock.write.use_closure(Box::new(|buf| Ok(buf.len())));
+        mock.read.use_closure(Box::new(move |_| {
+            let reply = &replies[index.get()];
+            index.set(index.get() + 1);
+            Ok(reply.as_bytes().to_vec())
+        }));
+        assert_eq!(Ok(()), mock.flash_auto("system", &data));
+        for sub_image in &sub_images {
+            assert!(mock.write.called_with(sub_image.clone()));
+        }
+        let flashes = mock
+            .write
+            .calls()
+            .iter()
+            .filter(|call| &call[..] == b"flash:system")
+            .count();
+        assert_eq!(4, flashes);
+        // The limit is queried once rather than for every sub-image
+        assert_eq!(
+            1,
+            mock.write
+                .calls()
+                .iter()
+                .filter(|call| &call[..] == b"getvar:max-download-size")
+                .count()
+        );
+
+        // Clients that don't report max-download-size get the data as is
+        let mut mock = MockUsb::default();
+        mock.write.use_closure(Box::new(|buf| Ok(buf.len())));
+        mock.read.use_closure(reply_sequence(vec![
+            "FAILunknown variable",
+            "DATA00030000",
+            "OKAY",
+        ]));
+        assert_eq!(Ok(()), mock.flash_auto("system", &data));
+        assert!(mock.write.called_with("download:00030000".as_bytes()));
+        assert!(mock.write.called_with(data.clone()));
+        assert!(mock.write.called_with("flash:system".as_bytes()));
+    }
+
+    #[test]
+    fn test_flash_file() {
+        let path = std::env::temp_dir().join("fastboot_test_flash_file.img");
+        let mut mock = MockUsb::default();
+
+        fs::write(&path, b"data").unwrap();
+        mock.write.use_closure(Box::new(|buf| Ok(buf.len())));
+        mock.read
+            .use_closure(reply_sequence(vec!["OKAY0x2000", "DATA00000004", "OKAY"]));
+        assert_eq!(
+            Ok(4),
+            mock.flash_file("boot", &path).map(|stats| stats.bytes)
+        );
+        assert!(mock.write.called_with("data".as_bytes()));
+        assert!(mock.write.called_with("flash:boot".as_bytes()));
+
+        // Clients that don't report max-download-size get it unsplit
+        let mut mock = MockUsb::default();
+        mock.write.use_closure(Box::new(|buf| Ok(buf.len())));
+        mock.read.use_closure(reply_sequence(vec![
+            "FAILunknown variable",
+            "DATA00000004",
+            "OKAY",
+        ]));
+        assert_eq!(
+            Ok(4),
+            mock.flash_file("boot", &path).map(|stats| stats.bytes)
+        );
+        assert!(mock.write.called_with("download:00000004".as_bytes()));
+        assert!(mock.write.called_with("flash:boot".as_bytes()));
+
+        // So do sparse images
+        fs::write(&path, sparse_image()).unwrap();
+        let mut mock = MockUsb::default();
+        mock.write.use_closure(Box::new(|buf| Ok(buf.len())));
+        mock.read.use_closure(reply_sequence(vec![
+            "FAILunknown variable",
+            "DATA00000064",
+            "OKAY",
+        ]));
+        assert_eq!(
+            Ok(100),
+            mock.flash_file("system", &path).map(|stats| stats.bytes)
+        );
+        assert!(mock.write.called_with(sparse_image()));
+        assert!(mock.write.called_with("flash:system".as_bytes()));
+
+        // Three blocks that don't fit into a single download
+        let data: Vec<u8> = (0..10000).map(|i| i as u8).collect();
+        fs::write(&path, &data).unwrap();
+        let mut mock = MockUsb::default();
+        mock.write.use_closure(Box::new(|buf| Ok(buf.len())));
+        mock.read.use_closure(reply_sequence(vec![
+            "OKAY0x2000",
+            "DATA00001034",
+            "OKAY",
+            "OKAY",
+            "DATA00001040",
+            "OKAY",
+            "OKAY",
+            "DATA00001034",
+            "OKAY",
+        ]));
+        assert_eq!(
+            Ok(10000),
+            mock.flash_file("system", &path).map(|stats| stats.bytes)
+        );
+        fs::remove_file(&path).unwrap();
+
+        let mut raw = Vec::new();
+        for sub_image in mock.write.calls() {
+            if let Ok(sub_image) = SparseImage::parse(&sub_image) {
+                assert_eq!(3, sub_image.total_blocks);
+                for chunk in sub_image.chunks {
+                    if let ChunkData::Raw(data) = chunk.data {
+                        raw.extend_from_slice(data);
+                    }
+                }
+            }
+        }
+        assert_eq!(&data[..], &raw[..data.len()]);
+        let flashes = mock
+            .write
+            .calls()
+            .into_iter()
+            .filter(|call| &call[..] == b"flash:system")
+            .count();
+        assert_eq!(3, flashes);
+        assert_eq!(
+            1,
+            mock.write
+                .calls()
+                .iter()
+                .filter(|call| &call[..] == b"getvar:max-download-size")
+                .count()
+        );
+    }
+
+    #[test]
+    fn test_flash_dir() {
+        let dir = std::env::temp_dir().join("fastboot_test_flash_dir");
+        fs::create_dir_all(&dir).unwrap();
+        for name in &[
+            "system.img",
+            "boot.img",
+            "bootloader.img",
+            "userdata.img",
+            "unknown.img",
+            "notes.txt",
+        ] {
+            fs::write(dir.join(name), b"data").unwrap();
+        }
+
+        let plan: Vec<String> = fastboot::plan_flash_dir(&dir)
+            .unwrap()
+            .iter()
+            .map(|step| step.to_string())
+            .collect();
+        let image = |name: &str| dir.join(name).display().to_string();
+        assert_eq!(
+            vec![
+                format!("flash bootloader {}", image("bootloader.img")),
+                "reboot-bootloader".to_owned(),
+                format!("flash boot {}", image("boot.img")),
+                format!("flash system {}", image("system.img")),
+            ],
+            plan
+        );
+
+        let image_replies = vec!["OKAY0x2000", "DATA00000004", "OKAY", "OKAY"];
+        let mut replies = image_replies.clone();
+        replies.push("OKAY");
+        let mut mock = MockUsb::default();
+        mock.write.use_closure(Box::new(|buf| Ok(buf.len())));
+        mock.read.use_closure(reply_sequence(replies));
+        let mut replies = image_replies.clone();
+        replies.extend_from_slice(&image_replies);
+        let rebooted = MockUsb::default();
+        rebooted.write.use_closure(Box::new(|buf| Ok(buf.len())));
+        rebooted.read.use_closure(reply_sequence(replies));
+        let before = mock.clone();
+        let reconnects = Cell::new(0);
+        assert_eq!(
+            Ok(()),
+            mock.flash_dir(&dir, || {
+                reconnects.set(reconnects.get() + 1);
+                Ok(rebooted.clone())
+            })
+        );
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(1, reconnects.get());
+
+        let commands = |mock: &MockUsb| -> Vec<Vec<u8>> {
+            mock.write
+                .calls()
+                .into_iter()
+                .filter(|call| call.starts_with(b"flash:") || call.starts_with(b"reboot"))
+                .collect()
+        };
+        assert_eq!(
+            vec![b"flash:bootloader".to_vec(), b"reboot-bootloader".to_vec()],
+            commands(&before)
+        );
+        assert_eq!(
+            vec![b"flash:boot".to_vec(), b"flash:system".to_vec()],
+            commands(&rebooted)
+        );
+    }
+
+    #[test]
+    fn test_send_command() {
+        let mut mock = MockUsb::default();
+
+        mock.write.return_value_for("vendor:xyz".as_bytes(), Ok(10));
+        mock.read
+            .use_closure(reply_sequence(vec!["INFOworking", "OKAYdone"]));
+        assert_eq!(
+            Ok(Reply::INFO("working".to_owned())),
+            fastboot::send_command(&mut mock, b"vendor:xyz")
+        );
+        assert_eq!(
+            Ok(Reply::OKAY("done".to_owned())),
+            fastboot::read_reply(&mut mock)
+        );
+    }
+
+    #[test]
+    fn test_disconnect() {
+        let mut mock = MockUsb::default();
+
+        // A client that resets in the middle of flashing
+        mock.write.use_closure(Box::new(|buf| Ok(buf.len())));
+        mock.read.use_closure(Box::new(|_| {
+            Err(CloneableError {
+                kind: io::ErrorKind::NotConnected,
+                description: "no device".to_owned(),
+            })
+        }));
+        assert_eq!(Err(FastbootError::DeviceDisconnected), mock.flash("boot"));
+    }
+
+    #[test]
+    fn test_timeouts() {
+        let mut mock = MockUsb::default();
+
+        mock.write.return_value_for("reboot".as_bytes(), Ok(6));
+        mock.read.return_value(Err(CloneableError {
+            kind: io::ErrorKind::TimedOut,
+            description: "timed out".to_owned(),
+        }));
+        assert_eq!(
+            Err(FastbootError::Timeout),
+            fastboot::send_command_with_timeouts(&mut mock, b"reboot", 3)
+        );
+        assert_eq!(3, mock.read.num_calls());
+
+        mock.read.reset_calls();
+        assert_eq!(Err(FastbootError::Timeout), mock.reboot());
+        assert_eq!(fastboot::DEFAULT_MAX_TIMEOUTS, mock.read.num_calls());
+    }
+
+    #[test]
+    fn test_logical_partitions() {
+        let mut mock = MockUsb::default();
+
+        mock.write.use_closure(Box::new(|buf| Ok(buf.len())));
+        mock.read
+            .use_closure(Box::new(|_| Ok("OKAY".as_bytes().to_vec())));
+        assert_eq!(Ok(()), mock.create_logical_partition("system_a", 4096));
+        assert!(mock
+            .write
+            .called_with("create-logical-partition:system_a:4096".as_bytes()));
+        assert_eq!(Ok(()), mock.resize_logical_partition("system_a", 8192));
+        assert!(mock
+            .write
+            .called_with("resize-logical-partition:system_a:8192".as_bytes()));
+        assert_eq!(Ok(()), mock.delete_logical_partition("system_a"));
+        assert!(mock
+            .write
+            .called_with("delete-logical-partition:system_a".as_bytes()));
+    }
+
+    #[cfg(feature = "tcp")]
+    mod tcp_tests {
+        use crate::fastboot::{self, Fastboot, Reply};
+        use crate::tcpio::TcpDevice;
+        use std::io::{Read, Write};
+        use std::net::{TcpListener, TcpStream};
+        use std::thread;
+
+        fn read_message(stream: &mut TcpStream) -> Vec<u8> {
+            let mut length = [0; 8];
+            stream.read_exact(&mut length).unwrap();
+            let mut message = vec![0; u64::from_be_bytes(length) as usize];
+            stream.read_exact(&mut message).unwrap();
+            message
+        }
+
+        fn write_message(stream: &mut TcpStream, message: &[u8]) {
+            stream
+                .write_all(&(message.len() as u64).to_be_bytes())
+                .unwrap();
+            stream.write_all(message).unwrap();
+        }
+
+        #[test]
+        fn test_tcp_device() {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = thread::spawn(move || {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut handshake = [0; 4];
+                stream.read_exact(&mut handshake).unwrap();
+                assert_eq!(b"FB01", &handshake);
+                stream.write_all(b"FB01").unwrap();
+
+                assert_eq!(b"getvar:version".to_vec(), read_message(&mut stream));
+                write_message(&mut stream, b"OKAY0.4");
+                assert_eq!(b"reboot".to_vec(), read_message(&mut stream));
+                write_message(&mut stream, b"INFOrebooting");
+                write_message(&mut stream, b"OKAY");
+            });
+
+            let mut device = TcpDevice::connect(addr).unwrap();
+            assert_eq!(Ok("0.4".to_owned()), device.getvar("version"));
+            assert_eq!(
+                Ok(Reply::INFO("rebooting".to_owned())),
+                fastboot::send_command(&mut device, b"reboot")
+            );
+            assert_eq!(
+                Ok(Reply::OKAY("".to_owned())),
+                fastboot::read_reply(&mut device)
+            );
+            server.join().unwrap();
+
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = thread::spawn(move || {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut handshake = [0; 4];
+                stream.read_exact(&mut handshake).unwrap();
+                stream.write_all(b"XX01").unwrap();
+            });
+            assert!(TcpDevice::connect(addr).is_err());
+            server.join().unwrap();
+        }
+    }
+
+    #[cfg(feature = "log")]
+    mod log_tests {
+        use super::{reply_sequence, MockUsb};
+        use crate::fastboot::Fastboot;
+        use log::{Log, Metadata, Record};
+        use std::sync::Mutex;
+
+        struct Recorder(Mutex<Vec<String>>);
+
+        impl Log for Recorder {
+            fn enabled(&self, _: &Metadata) -> bool {
+                true
+            }
+
+            fn log(&self, record: &Record) {
+                self.0.lock().unwrap().push(record.args().to_string());
+            }
+
+            fn flush(&self) {}
+        }
+
+        static RECORDER: Recorder = Recorder(Mutex::new(Vec::new()));
+
+        #[test]
+        fn test_trace() {
+            log::set_logger(&RECORDER).unwrap();
+            log::set_max_level(log::LevelFilter::Debug);
+
+            let mut mock = MockUsb::default();
+            mock.write
+                .return_value_for("getvar:version".as_bytes(), Ok(14));
+            mock.read.use_closure(reply_sequence(vec!["OKAY1.0"]));
+            assert_eq!(Ok("1.0".to_owned()), mock.getvar("version"));
+
+            let lines = RECORDER.0.lock().unwrap();
+            assert!(lines.contains(&r#"-> "getvar:version""#.to_owned()));
+            assert!(lines.contains(&r#"<- "OKAY1.0""#.to_owned()));
+        }
+    }
+
+    #[cfg(feature = "test-util")]
+    mod scripted_tests {
+        use crate::fastboot::{Fastboot, FastbootError};
+        use crate::scripted::ScriptedTransport;
+
+        #[test]
+        fn test_scripted_transport() {
+            let mut transport = ScriptedTransport::new(vec![
+                ("getvar:max-download-size", "OKAY0x1000"),
+                ("download:00000004", "DATA00000004"),
+                ("data", ""),
+                ("", "INFOchecking"),
+                ("", "OKAY"),
+                ("erase:boot", "FAILbusy"),
+            ]);
+            assert_eq!(Ok(()), transport.download("data".as_bytes()));
+            assert!(!transport.is_done());
+            assert_eq!(
+                Err(FastbootError::Fail("busy".to_owned())),
+                transport.erase("boot")
+            );
+            assert!(transport.is_done());
+
+            let mut transport = ScriptedTransport::new(vec![("reboot", "OKAY")]);
+            match transport.flash("boot") {
+                Err(FastbootError::Io(message)) => assert!(message.contains("reboot")),
+                result => panic!("Unexpected result: {:?}", result),
+            }
+            // A rejected write doesn't advance the script
+            assert_eq!(Ok(()), transport.reboot());
+            assert!(transport.is_done());
+            match transport.reboot() {
+                Err(FastbootError::Io(_)) => (),
+                result => panic!("Unexpected result: {:?}", result),
+            }
+        }
+
+        #[test]
+        fn test_scripted_long_reply() {
+            let value = "x".repeat(600);
+            let reply = format!("OKAY{}", value);
+            let mut transport = ScriptedTransport::new(vec![("getvar:serialno", reply.as_str())]);
+            assert_eq!(Ok(value), transport.getvar("serialno"));
+            assert!(transport.is_done());
+        }
+    }
+
+    #[cfg(feature = "zip")]
+    mod update_tests {
+        use super::{reply_sequence, MockUsb};
+        use crate::fastboot::{Fastboot, FastbootError};
+        use crate::update::UpdatePackage;
+        use std::cell::Cell;
+        use std::fs;
+        use std::io::{Cursor, Write};
+        use zip::write::FileOptions;
+        use zip::{CompressionMethod, ZipWriter};
+
+        fn update_package(files: &[(&str, &[u8])]) -> Vec<u8> {
+            let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+            let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+            for (name, data) in files {
+                zip.start_file(*name, options).unwrap();
+                zip.write_all(data).unwrap();
+            }
+            zip.finish().unwrap().into_inner()
+        }
+
+        #[test]
+        fn test_update_package() {
+            let package = update_package(&[
+                (
+                    "android-info.txt",
+                    b"require board=fb|other\nrequire version-bootloader=1.*\n",
+                ),
+                ("boot.img", b"boot"),
+                ("bootloader.img", b"blob"),
+                ("META/misc_info.txt", b"ignored"),
+            ]);
+            let mut package = UpdatePackage::new(Cursor::new(package)).unwrap();
+            assert_eq!(vec!["bootloader", "boot"], package.partitions());
+
+            let mut mock = MockUsb::default();
+            mock.write.use_closure(Box::new(|buf| Ok(buf.len())));
+            mock.read
+                .use_closure(reply_sequence(vec!["OKAYfb", "OKAY1.2"]));
+            assert_eq!(Ok(()), package.check_requirements(&mut mock));
+            assert!(mock.write.called_with("getvar:product".as_bytes()));
+            assert!(mock
+                .write
+                .called_with("getvar:version-bootloader".as_bytes()));
+
+            mock.read
+                .use_closure(reply_sequence(vec!["OKAYfb", "OKAY2.0"]));
+            match package.check_requirements(&mut mock) {
+                Err(FastbootError::Protocol(_)) => (),
+                result => panic!("Unexpected result: {:?}", result),
+            }
+
+            // The client is back on a new connection after the reboot
+            let mut mock = MockUsb::default();
+            mock.write.use_closure(Box::new(|buf| Ok(buf.len())));
+            mock.read.use_closure(reply_sequence(vec![
+                "OKAY0x1000",
+                "DATA00000004",
+                "OKAY",
+                "OKAY",
+                "OKAY",
+            ]));
+            let rebooted = MockUsb::default();
+            rebooted.write.use_closure(Box::new(|buf| Ok(buf.len())));
+            rebooted
+                .read
+                .use_closure(reply_sequence(vec!["OKAY0x1000", "DATA00000004", "OKAY"]));
+            let before = mock.clone();
+            let reconnects = Cell::new(0);
+            assert_eq!(
+                Ok(()),
+                package.flash_all(&mut mock, || {
+                    reconnects.set(reconnects.get() + 1);
+                    Ok(rebooted.clone())
+                })
+            );
+            assert_eq!(1, reconnects.get());
+            let commands = |mock: &MockUsb| -> Vec<Vec<u8>> {
+                mock.write
+                    .calls()
+                    .into_iter()
+                    .filter(|call| call.starts_with(b"flash:") || call.starts_with(b"reboot"))
+                    .collect()
+            };
+            assert_eq!(
+                vec![b"flash:bootloader".to_vec(), b"reboot-bootloader".to_vec()],
+                commands(&before)
+            );
+            assert_eq!(vec![b"flash:boot".to_vec()], commands(&rebooted));
+        }
+
+        #[test]
+        fn test_update_from_zip() {
+            let path = std::env::temp_dir().join("fastboot_test_update.zip");
+            fs::write(&path, update_package(&[("boot.img", b"boot")])).unwrap();
+            let mut mock = MockUsb::default();
+            mock.write.use_closure(Box::new(|buf| Ok(buf.len())));
+            mock.read
+                .use_closure(reply_sequence(vec!["OKAY0x1000", "DATA00000004", "OKAY"]));
+            assert_eq!(Ok(()), mock.update_from_zip(&path, || unreachable!()));
+            assert!(mock.write.called_with("boot".as_bytes()));
+            assert!(mock.write.called_with("flash:boot".as_bytes()));
+            fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[cfg(feature = "async")]
+    mod async_tests {
+        use crate::async_fastboot::AsyncFastboot;
+        use crate::fastboot::FastbootError;
+        use std::collections::VecDeque;
+        use std::io;
+        use std::pin::Pin;
+        use std::task::{Context, Poll};
+        use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+        // Replies with each of `replies` in turn and records everything written
+        struct AsyncMock {
+            replies: VecDeque<&'static [u8]>,
+            written: Vec<Vec<u8>>,
+        }
+
+        impl AsyncRead for AsyncMock {
+            fn poll_read(
+                mut self: Pin<&mut Self>,
+                _: &mut Context,
+                buf: &mut ReadBuf,
+            ) -> Poll<io::Result<()>> {
+                if let Some(reply) = self.replies.pop_front() {
+                    buf.put_slice(reply);
+                }
+                Poll::Ready(Ok(()))
+            }
+        }
+
+        impl AsyncWrite for AsyncMock {
+            fn poll_write(
+                mut self: Pin<&mut Self>,
+                _: &mut Context,
+                buf: &[u8],
+            ) -> Poll<io::Result<usize>> {
+                self.written.push(buf.to_vec());
+                Poll::Ready(Ok(buf.len()))
+            }
+
+            fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+                Poll::Ready(Ok(()))
+            }
+
+            fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+                Poll::Ready(Ok(()))
+            }
+        }
+
+        #[tokio::test]
+        async fn test_async_fastboot() {
+            let mut mock = AsyncMock {
+                replies: vec![
+                    &b"OKAY1.0"[..],
+                    b"OKAY0x1000",
+                    b"DATA00000004",
+                    b"OKAY",
+                    b"INFOwriting",
+                    b"OKAY",
+                    b"FAILno such partition",
+                    b"OKAY",
+                ]
+                .into(),
+                written: Vec::new(),
+            };
+
+            assert_eq!(Ok("1.0".to_owned()), mock.getvar("version").await);
+            assert_eq!(Ok(()), mock.download(b"data").await);
+            assert_eq!(Ok(()), mock.flash("boot").await);
+            assert_eq!(
+                Err(FastbootError::Fail("no such partition".to_owned())),
+                mock.erase("something").await
+            );
+            assert_eq!(Ok(()), mock.reboot().await);
+
+            let written: Vec<&[u8]> = mock.written.iter().map(|call| &call[..]).collect();
+            assert_eq!(
+                vec![
+                    &b"getvar:version"[..],
+                    b"getvar:max-download-size",
+                    b"download:00000004",
+                    b"data",
+                    b"flash:boot",
+                    b"erase:something",
+                    b"reboot",
+                ],
+                written
+            );
+        }
+
+        #[test]
+        fn test_async_fastboot_send() {
+            fn assert_send<F: Send>(_: F) {}
+
+            let mut mock = AsyncMock {
+                replies: VecDeque::new(),
+                written: Vec::new(),
+            };
+            assert_send(mock.getvar("version"));
+            assert_send(mock.download(b"data"));
+            assert_send(mock.flash("boot"));
+            assert_send(mock.erase("boot"));
+            assert_send(mock.reboot());
+        }
     }
 }
@@ -0,0 +1,105 @@
+//! Framing of Fastboot commands and replies that only depends on `core`.
+//!
+//! Everything here works on borrowed bytes and caller-provided buffers, so it
+//! can be used without `std` or an allocator, e.g. by a flashing jig that
+//! talks to clients over its own USB stack. [`Reply`] is decoded with it.
+//!
+//! Build the crate with `default-features = false` to get only this module.
+//!
+//! [`Reply`]: ../fastboot/enum.Reply.html
+
+use core::str;
+
+/// Length of the prefix every reply starts with, e.g. `OKAY`.
+pub const REPLY_PREFIX_LEN: usize = 4;
+/// Length of a `download:` command, which always has 8 hex digits.
+pub const DOWNLOAD_COMMAND_LEN: usize = DOWNLOAD_PREFIX.len() + 8;
+
+const DOWNLOAD_PREFIX: &[u8] = b"download:";
+const HEX_DIGITS: &[u8] = b"0123456789abcdef";
+
+/// A reply sent by a client, borrowing its payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RawReply<'a> {
+    /// A command succeeded, with an optional payload.
+    Okay(&'a [u8]),
+    /// A client is ready to send or receive this many bytes.
+    Data(usize),
+    /// A command failed with the enclosed message.
+    Fail(&'a [u8]),
+    /// An informational message, more replies will follow.
+    Info(&'a [u8]),
+    /// Human-readable output, more replies will follow.
+    Text(&'a [u8]),
+}
+
+/// Reasons for a reply not to be decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The reply is shorter than its prefix.
+    Truncated,
+    /// The size of a `DATA` reply isn't a hex number.
+    InvalidDataSize,
+    /// The reply starts with an unknown prefix.
+    UnknownPrefix,
+}
+
+/// Decodes a single reply, as received by a single read.
+///
+/// Replies never contain NUL bytes, so the payload ends at the first one.
+/// What follows is most likely stale data left in a buffer, e.g. after an
+/// aborted transfer.
+pub fn decode_reply(reply: &[u8]) -> Result<RawReply<'_>, DecodeError> {
+    if reply.len() < REPLY_PREFIX_LEN {
+        return Err(DecodeError::Truncated);
+    }
+
+    let (prefix, payload) = reply.split_at(REPLY_PREFIX_LEN);
+    let payload = match payload.iter().position(|&byte| byte == 0) {
+        Some(end) => &payload[..end],
+        None => payload,
+    };
+    match prefix {
+        b"OKAY" => Ok(RawReply::Okay(payload)),
+        b"INFO" => Ok(RawReply::Info(payload)),
+        b"TEXT" => Ok(RawReply::Text(payload)),
+        b"FAIL" => Ok(RawReply::Fail(payload)),
+        b"DATA" => str::from_utf8(payload)
+            .ok()
+            .and_then(|size| usize::from_str_radix(size, 16).ok())
+            .map(RawReply::Data)
+            .ok_or(DecodeError::InvalidDataSize),
+        _ => Err(DecodeError::UnknownPrefix),
+    }
+}
+
+/// Writes `command` followed by `argument` into `buf`, e.g. `getvar:` and
+/// `product`, returning the length of the result.
+///
+/// Gives `None` if `buf` is too small.
+pub fn encode_command(buf: &mut [u8], command: &[u8], argument: &[u8]) -> Option<usize> {
+    let len = command.len() + argument.len();
+    let buf = buf.get_mut(..len)?;
+    buf[..command.len()].copy_from_slice(command);
+    buf[command.len()..].copy_from_slice(argument);
+    Some(len)
+}
+
+/// Writes a `download:` command announcing `size` bytes into `buf`,
+/// returning the length of the result.
+///
+/// Gives `None` if `buf` is shorter than [`DOWNLOAD_COMMAND_LEN`] or `size`
+/// doesn't fit into 8 hex digits.
+///
+/// [`DOWNLOAD_COMMAND_LEN`]: constant.DOWNLOAD_COMMAND_LEN.html
+pub fn encode_download(buf: &mut [u8], size: u64) -> Option<usize> {
+    if size > u64::from(u32::MAX) {
+        return None;
+    }
+
+    let mut digits = [0; 8];
+    for (i, digit) in digits.iter_mut().enumerate() {
+        *digit = HEX_DIGITS[(size >> (28 - 4 * i) & 0xf) as usize];
+    }
+    encode_command(buf, DOWNLOAD_PREFIX, &digits)
+}
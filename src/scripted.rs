@@ -0,0 +1,155 @@
+//! A scripted transport for testing code built on [`Fastboot`] without a device.
+//!
+//! A script is a list of `(expected_write, reply)` steps, played in order:
+//!
+//! - Every write must match `expected_write` of the next step byte for byte,
+//!   otherwise it fails with [`ErrorKind::InvalidInput`]. Data sent during a
+//!   download is written in chunks of up to 1 MiB, one step per chunk.
+//! - Once a write matches, `reply` becomes readable. An empty `reply` means
+//!   the client doesn't reply, e.g. to downloaded data.
+//! - Steps with an empty `expected_write` don't wait for a write, their
+//!   `reply` follows the previous one. That's how a command gets several
+//!   replies, e.g. `INFO` lines before `OKAY`.
+//! - A read never writes past its buffer. What's left of a longer reply is
+//!   returned by the following reads, as with a client that splits it into
+//!   packets.
+//! - Reading with no reply pending fails with [`ErrorKind::UnexpectedEof`].
+//!
+//! ```
+//! use fastboot::fastboot::Fastboot;
+//! use fastboot::scripted::ScriptedTransport;
+//!
+//! let mut transport = ScriptedTransport::new(vec![
+//!     ("getvar:max-download-size", "OKAY0x1000"),
+//!     ("download:00000004", "DATA00000004"),
+//!     ("boot", ""),
+//!     ("", "INFOchecking"),
+//!     ("", "OKAY"),
+//!     ("flash:boot", "OKAY"),
+//! ]);
+//! transport.download(b"boot").unwrap();
+//! transport.flash("boot").unwrap();
+//! assert!(transport.is_done());
+//! ```
+//!
+//! Enabled by the `test-util` feature.
+//!
+//! [`Fastboot`]: ../fastboot/trait.Fastboot.html
+//! [`ErrorKind::InvalidInput`]: https://doc.rust-lang.org/std/io/enum.ErrorKind.html#variant.InvalidInput
+//! [`ErrorKind::UnexpectedEof`]: https://doc.rust-lang.org/std/io/enum.ErrorKind.html#variant.UnexpectedEof
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+
+/// A transport that replays a script, see the [module documentation].
+///
+/// [module documentation]: index.html
+#[derive(Debug, Clone)]
+pub struct ScriptedTransport {
+    steps: VecDeque<(Vec<u8>, Vec<u8>)>,
+    replies: VecDeque<Vec<u8>>,
+}
+
+impl ScriptedTransport {
+    /// Creates a transport playing `script`.
+    pub fn new<I, W, R>(script: I) -> Self
+    where
+        I: IntoIterator<Item = (W, R)>,
+        W: AsRef<[u8]>,
+        R: AsRef<[u8]>,
+    {
+        let mut transport = ScriptedTransport {
+            steps: script
+                .into_iter()
+                .map(|(write, reply)| (write.as_ref().to_vec(), reply.as_ref().to_vec()))
+                .collect(),
+            replies: VecDeque::new(),
+        };
+        transport.queue_unprompted();
+        transport
+    }
+
+    /// Checks whether every step has been played and every reply read.
+    pub fn is_done(&self) -> bool {
+        self.steps.is_empty() && self.replies.is_empty()
+    }
+
+    // Queues replies of the following steps that don't wait for a write
+    fn queue_unprompted(&mut self) {
+        while self
+            .steps
+            .front()
+            .is_some_and(|(write, _)| write.is_empty())
+        {
+            if let Some((_, reply)) = self.steps.pop_front() {
+                self.queue(reply);
+            }
+        }
+    }
+
+    fn queue(&mut self, reply: Vec<u8>) {
+        if !reply.is_empty() {
+            self.replies.push_back(reply);
+        }
+    }
+}
+
+impl Read for ScriptedTransport {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let mut reply = self
+            .replies
+            .pop_front()
+            .ok_or_else(|| Error::new(ErrorKind::UnexpectedEof, "No reply scripted"))?;
+        let len = cmp::min(reply.len(), buf.len());
+        buf[..len].copy_from_slice(&reply[..len]);
+        if len < reply.len() {
+            self.replies.push_front(reply.split_off(len));
+        }
+        Ok(len)
+    }
+}
+
+impl Write for ScriptedTransport {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        match self.steps.front() {
+            Some((expected, _)) if expected.as_slice() == buf => (),
+            Some((expected, _)) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "Expected write of {:?}, got {:?}",
+                        String::from_utf8_lossy(expected),
+                        String::from_utf8_lossy(buf)
+                    ),
+                ))
+            }
+            None => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "Unexpected write of {:?} after the script",
+                        String::from_utf8_lossy(buf)
+                    ),
+                ))
+            }
+        }
+        if let Some((_, reply)) = self.steps.pop_front() {
+            self.queue(reply);
+        }
+        self.queue_unprompted();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
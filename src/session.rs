@@ -0,0 +1,379 @@
+//! A Fastboot session that remembers what it learned about a client.
+//!
+//! [`Fastboot`] methods are implemented for any transport, so they query the
+//! client every time they need e.g. its `max-download-size`. A
+//! [`FastbootSession`] owns the transport and caches such values instead,
+//! which saves a round trip per command in multi-step flashing.
+//!
+//! [`Fastboot`]: ../fastboot/trait.Fastboot.html
+//! [`FastbootSession`]: struct.FastbootSession.html
+
+use std::fs::File;
+use std::io::{Error, Read, Result, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::fastboot::{
+    check_download_size, check_partition, fb_download, fb_download_stream, fb_flash_auto,
+    fb_flash_offset, fb_flash_reader, fb_flash_sparse, fb_run_sequence, fb_signature, known_limit,
+    measure_transfer, slot_partition, Fastboot, FastbootError, FbResult, Step, StepResult,
+    TransferStats, CURRENT_SLOT_VAR, DOWNLOAD_CHUNK_LEN, FB_MAX_REPLY_LEN,
+};
+
+/// A transport with cached per-session client state.
+///
+/// All [`Fastboot`] methods are available on it. Inherent methods with the
+/// same names shadow the trait ones to use the cache, that's every method
+/// that downloads data but [`flash_dir`] and [`update_from_zip`], which
+/// query `max-download-size` once per image.
+///
+/// [`Fastboot`]: ../fastboot/trait.Fastboot.html
+/// [`flash_dir`]: ../fastboot/trait.Fastboot.html#method.flash_dir
+/// [`update_from_zip`]: ../fastboot/trait.Fastboot.html#method.update_from_zip
+pub struct FastbootSession<T: Read + Write> {
+    io: T,
+    max_download_size: Option<usize>,
+    current_slot: Option<String>,
+    cancel: Option<Arc<AtomicBool>>,
+    reply_buffer_len: Option<usize>,
+    // What's left of the last read into the reply buffer
+    pending: Vec<u8>,
+}
+
+impl<T: Read + Write> FastbootSession<T> {
+    /// Starts a session over `io`. Nothing is queried until it's needed.
+    pub fn new(io: T) -> Self {
+        FastbootSession {
+            io,
+            max_download_size: None,
+            current_slot: None,
+            cancel: None,
+            reply_buffer_len: None,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Makes every operation fail with [`FastbootError::Cancelled`] once
+    /// `cancel` is set, e.g. from a UI thread.
+    ///
+    /// The flag is checked before each transfer, including retries after a
+    /// timeout while waiting for a reply, so a transfer in flight is always
+    /// completed. The client may still be busy with a cancelled command and
+    /// is best rebooted or reconnected before going on. The flag isn't
+    /// cleared by the session.
+    ///
+    /// [`FastbootError::Cancelled`]: ../fastboot/enum.FastbootError.html#variant.Cancelled
+    pub fn set_cancel_flag(&mut self, cancel: Arc<AtomicBool>) {
+        self.cancel = Some(cancel);
+    }
+
+    /// Reads replies into a buffer of `len` bytes, for clients with
+    /// nonstandard framing.
+    ///
+    /// Replies are otherwise read 512 bytes at a time, which truncates longer
+    /// ones from clients that don't split them into packets. Smaller reads
+    /// get what's left of the buffer before anything else is read.
+    ///
+    /// This only grows the buffer, so `len` below 512 bytes is refused,
+    /// although any reply fits above the 4-byte prefix. A reply that doesn't
+    /// fill a 512-byte read is taken as complete, so a smaller buffer would
+    /// cut longer replies short instead of reading them in parts.
+    pub fn set_reply_buffer_len(&mut self, len: usize) -> FbResult<()> {
+        if len < FB_MAX_REPLY_LEN {
+            return Err(FastbootError::Protocol(format!(
+                "Reply buffer of {} bytes is smaller than the {} bytes replies are read with",
+                len, FB_MAX_REPLY_LEN
+            )));
+        }
+        self.reply_buffer_len = Some(len);
+        Ok(())
+    }
+
+    fn check_cancelled(&self) -> Result<()> {
+        match self.cancel {
+            Some(ref cancel) if cancel.load(Ordering::SeqCst) => {
+                Err(Error::other(FastbootError::Cancelled))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Gets a reference to the underlying transport.
+    pub fn get_ref(&self) -> &T {
+        &self.io
+    }
+
+    /// Gets a mutable reference to the underlying transport.
+    ///
+    /// Commands sent directly through it bypass the cache.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.io
+    }
+
+    /// Ends the session, returning the underlying transport.
+    pub fn into_inner(self) -> T {
+        self.io
+    }
+
+    /// Forgets all cached values, e.g. after the client has been rebooted.
+    pub fn invalidate(&mut self) {
+        self.max_download_size = None;
+        self.current_slot = None;
+    }
+
+    /// Gets the biggest payload a client accepts in a single download,
+    /// querying it only once.
+    pub fn max_download_size(&mut self) -> FbResult<usize> {
+        match self.max_download_size {
+            Some(size) => Ok(size),
+            None => {
+                let size = Fastboot::max_download_size(self)?;
+                self.max_download_size = Some(size);
+                Ok(size)
+            }
+        }
+    }
+
+    // Gets the cached `max-download-size`, see `fastboot::known_limit()`
+    fn download_limit(&mut self) -> FbResult<Option<usize>> {
+        known_limit(self.max_download_size())
+    }
+
+    /// Gets the currently active slot, querying it only once.
+    pub fn current_slot(&mut self) -> FbResult<String> {
+        match self.current_slot {
+            Some(ref slot) => Ok(slot.clone()),
+            None => {
+                let slot = Fastboot::getvar(self, CURRENT_SLOT_VAR)?;
+                self.current_slot = Some(slot.clone());
+                Ok(slot)
+            }
+        }
+    }
+
+    /// Sets the active slot, updating the cached one on success.
+    pub fn set_active(&mut self, slot: &str) -> FbResult<()> {
+        Fastboot::set_active(self, slot)?;
+        self.current_slot = Some(slot.to_owned());
+        Ok(())
+    }
+
+    /// Downloads `data` and flashes it into `base_partition` of the active slot.
+    ///
+    /// See [`Fastboot::flash_current_slot`], the slot is the cached one.
+    ///
+    /// [`Fastboot::flash_current_slot`]: ../fastboot/trait.Fastboot.html#method.flash_current_slot
+    pub fn flash_current_slot(&mut self, base_partition: &str, data: &[u8]) -> FbResult<()> {
+        check_partition(base_partition)?;
+        let partition = slot_partition(base_partition, self.current_slot())?;
+        self.download(data)?;
+        Fastboot::flash(self, &partition)
+    }
+
+    /// Downloads provided data into a client.
+    ///
+    /// See [`Fastboot::download`], the limit is checked against the cached one.
+    ///
+    /// [`Fastboot::download`]: ../fastboot/trait.Fastboot.html#method.download
+    pub fn download(&mut self, data: &[u8]) -> FbResult<()> {
+        self.download_with_progress(data, &mut |_, _| {})
+    }
+
+    /// Downloads provided data into a client, reporting progress.
+    ///
+    /// See [`Fastboot::download_with_progress`].
+    ///
+    /// [`Fastboot::download_with_progress`]: ../fastboot/trait.Fastboot.html#method.download_with_progress
+    pub fn download_with_progress(
+        &mut self,
+        data: &[u8],
+        on_progress: &mut dyn FnMut(u64, u64),
+    ) -> FbResult<()> {
+        check_download_size(self.download_limit()?, data.len() as u64)?;
+        fb_download(self, data, DOWNLOAD_CHUNK_LEN, on_progress)
+    }
+
+    /// Downloads provided data into a client in chunks, waiting in between.
+    ///
+    /// See [`Fastboot::download_throttled`].
+    ///
+    /// [`Fastboot::download_throttled`]: ../fastboot/trait.Fastboot.html#method.download_throttled
+    pub fn download_throttled(
+        &mut self,
+        data: &[u8],
+        chunk_size: usize,
+        delay: Duration,
+    ) -> FbResult<()> {
+        check_download_size(self.download_limit()?, data.len() as u64)?;
+        fb_download(self, data, chunk_size, &mut |sent, total| {
+            if sent < total {
+                thread::sleep(delay);
+            }
+        })
+    }
+
+    /// Downloads `len` bytes read from `source` into a client.
+    ///
+    /// See [`Fastboot::download_stream`].
+    ///
+    /// [`Fastboot::download_stream`]: ../fastboot/trait.Fastboot.html#method.download_stream
+    pub fn download_stream<R: Read>(&mut self, source: R, len: u64) -> FbResult<TransferStats> {
+        check_download_size(self.download_limit()?, len)?;
+        measure_transfer(len, || fb_download_stream(self, source, len))
+    }
+
+    /// Stages `data` for a following command that consumes it.
+    ///
+    /// See [`Fastboot::stage`].
+    ///
+    /// [`Fastboot::stage`]: ../fastboot/trait.Fastboot.html#method.stage
+    pub fn stage(&mut self, data: &[u8]) -> FbResult<()> {
+        self.download(data)
+    }
+
+    /// Downloads `data` and flashes it into a specified partition.
+    ///
+    /// See [`Fastboot::download_and_flash`].
+    ///
+    /// [`Fastboot::download_and_flash`]: ../fastboot/trait.Fastboot.html#method.download_and_flash
+    pub fn download_and_flash(&mut self, partition: &str, data: &[u8]) -> FbResult<()> {
+        check_partition(partition)?;
+        self.download(data)?;
+        Fastboot::flash(self, partition)
+    }
+
+    /// Downloads `data` and flashes it into a raw partition at `offset` bytes
+    /// from its start.
+    ///
+    /// See [`Fastboot::flash_at`].
+    ///
+    /// [`Fastboot::flash_at`]: ../fastboot/trait.Fastboot.html#method.flash_at
+    pub fn flash_at(&mut self, partition: &str, offset: u64, data: &[u8]) -> FbResult<()> {
+        check_partition(partition)?;
+        self.download(data)?;
+        fb_flash_offset(self, partition, offset)
+    }
+
+    /// Flashes an Android sparse image into a specified partition.
+    ///
+    /// See [`Fastboot::flash_sparse`], sub-images fit into the cached limit.
+    ///
+    /// [`Fastboot::flash_sparse`]: ../fastboot/trait.Fastboot.html#method.flash_sparse
+    pub fn flash_sparse(&mut self, partition: &str, image: &[u8]) -> FbResult<()> {
+        check_partition(partition)?;
+        let limit = self.download_limit()?;
+        fb_flash_sparse(self, limit, partition, image)
+    }
+
+    /// Flashes `data` into a specified partition, splitting it if needed.
+    ///
+    /// See [`Fastboot::flash_auto`], sub-images fit into the cached limit.
+    ///
+    /// [`Fastboot::flash_auto`]: ../fastboot/trait.Fastboot.html#method.flash_auto
+    pub fn flash_auto(&mut self, partition: &str, data: &[u8]) -> FbResult<()> {
+        check_partition(partition)?;
+        let limit = self.download_limit()?;
+        fb_flash_auto(self, limit, partition, data)
+    }
+
+    /// Flashes a file into a specified partition.
+    ///
+    /// See [`Fastboot::flash_file`], chunks fit into the cached limit.
+    ///
+    /// [`Fastboot::flash_file`]: ../fastboot/trait.Fastboot.html#method.flash_file
+    pub fn flash_file(&mut self, partition: &str, path: &Path) -> FbResult<TransferStats> {
+        check_partition(partition)?;
+        let file = File::open(path)?;
+        let len = file.metadata()?.len() as usize;
+        measure_transfer(len as u64, || {
+            let limit = self.download_limit()?;
+            fb_flash_reader(self, limit, partition, file, len)
+        })
+    }
+
+    /// Sends a signature that secure clients check before allowing to flash.
+    ///
+    /// See [`Fastboot::send_signature`].
+    ///
+    /// [`Fastboot::send_signature`]: ../fastboot/trait.Fastboot.html#method.send_signature
+    pub fn send_signature(&mut self, signature: &[u8]) -> FbResult<()> {
+        self.download(signature)?;
+        fb_signature(self)
+    }
+
+    /// Downloads `data` and runs a vendor-specific command that consumes it.
+    ///
+    /// See [`Fastboot::oem_with_data`].
+    ///
+    /// [`Fastboot::oem_with_data`]: ../fastboot/trait.Fastboot.html#method.oem_with_data
+    pub fn oem_with_data(&mut self, subcommand: &str, data: &[u8]) -> FbResult<String> {
+        self.download(data)?;
+        Fastboot::oem(self, subcommand)
+    }
+
+    /// Runs `steps` in order, stopping at the first one that fails.
+    ///
+    /// See [`Fastboot::run_sequence`], downloads are checked against the
+    /// cached limit.
+    ///
+    /// [`Fastboot::run_sequence`]: ../fastboot/trait.Fastboot.html#method.run_sequence
+    pub fn run_sequence(&mut self, steps: &[Step]) -> FbResult<Vec<StepResult>> {
+        fb_run_sequence(self, steps, &mut |session, data| session.download(data))
+    }
+}
+
+#[cfg(feature = "usb")]
+impl<'a> FastbootSession<crate::usb::UsbDevice<'a>> {
+    /// Starts a session over a USB device, with replies read into a buffer
+    /// that fits a packet of its IN endpoint.
+    ///
+    /// Replies are read 512 bytes at a time, which fits high-speed packets
+    /// but not SuperSpeed ones of up to 1024 bytes. Endpoints with smaller
+    /// packets keep the default, see [`set_reply_buffer_len`].
+    ///
+    /// [`set_reply_buffer_len`]: #method.set_reply_buffer_len
+    pub fn new_usb(device: crate::usb::UsbDevice<'a>) -> Self {
+        let packet_size = device.in_max_packet_size() as usize;
+        let mut session = FastbootSession::new(device);
+        if packet_size > FB_MAX_REPLY_LEN {
+            session.reply_buffer_len = Some(packet_size);
+        }
+        session
+    }
+}
+
+impl<T: Read + Write> Read for FastbootSession<T> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.pending.is_empty() {
+            self.check_cancelled()?;
+            match self.reply_buffer_len {
+                Some(len) if len > buf.len() => {
+                    let mut reply = vec![0; len];
+                    let received = self.io.read(&mut reply)?;
+                    reply.truncate(received);
+                    self.pending = reply;
+                }
+                _ => return self.io.read(buf),
+            }
+        }
+
+        let len = std::cmp::min(buf.len(), self.pending.len());
+        buf[..len].copy_from_slice(&self.pending[..len]);
+        self.pending.drain(..len);
+        Ok(len)
+    }
+}
+
+impl<T: Read + Write> Write for FastbootSession<T> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.check_cancelled()?;
+        self.io.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.io.flush()
+    }
+}
@@ -0,0 +1,330 @@
+//! Parser and splitter for Android sparse images.
+//!
+//! See system/core/libsparse/sparse_format.h in AOSP for the format description.
+
+use crate::fastboot::{FastbootError, FbResult};
+
+/// Magic number every sparse image starts with.
+pub const SPARSE_HEADER_MAGIC: u32 = 0xed26_ff3a;
+
+/// Block size used when wrapping raw data into sparse images.
+pub const DEFAULT_BLOCK_SIZE: u32 = 4096;
+
+const SPARSE_HEADER_LEN: usize = 28;
+const CHUNK_HEADER_LEN: usize = 12;
+// Header plus leading and trailing DONT_CARE chunks
+const SPLIT_OVERHEAD: usize = SPARSE_HEADER_LEN + 2 * CHUNK_HEADER_LEN;
+
+const CHUNK_TYPE_RAW: u16 = 0xcac1;
+const CHUNK_TYPE_FILL: u16 = 0xcac2;
+const CHUNK_TYPE_DONT_CARE: u16 = 0xcac3;
+const CHUNK_TYPE_CRC32: u16 = 0xcac4;
+
+/// Contents of a sparse image chunk.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChunkData<'a> {
+    /// Blocks stored as-is.
+    Raw(&'a [u8]),
+    /// Blocks filled with a repeated 4-byte value.
+    Fill(u32),
+    /// Blocks that should be left untouched.
+    DontCare,
+    /// Checksum of all the data up to this chunk.
+    Crc32(u32),
+}
+
+/// A single chunk of a sparse image, covering `blocks` output blocks.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chunk<'a> {
+    pub blocks: u32,
+    pub data: ChunkData<'a>,
+}
+
+/// A parsed sparse image, borrowing RAW data from the original buffer.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SparseImage<'a> {
+    pub block_size: u32,
+    pub total_blocks: u32,
+    pub chunks: Vec<Chunk<'a>>,
+}
+
+fn invalid(message: &str) -> FastbootError {
+    FastbootError::Protocol(format!("Invalid sparse image: {}", message))
+}
+
+fn read_u16(buf: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([buf[at], buf[at + 1]])
+}
+
+fn read_u32(buf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]])
+}
+
+/// Gets how many bytes of raw data [`wrap_raw`] fits into `max_size` bytes.
+///
+/// [`wrap_raw`]: fn.wrap_raw.html
+pub fn raw_capacity(block_size: u32, max_size: usize) -> usize {
+    let block_size = block_size as usize;
+    max_size.saturating_sub(SPLIT_OVERHEAD + CHUNK_HEADER_LEN) / block_size * block_size
+}
+
+/// Wraps raw `data` into a sparse image that writes it at the `start` block
+/// of a partition spanning `total_blocks`, leaving all other blocks untouched.
+///
+/// `data` is padded with zeros to a whole number of blocks.
+pub fn wrap_raw(block_size: u32, total_blocks: u32, start: u32, data: &[u8]) -> Vec<u8> {
+    let block_len = block_size as usize;
+    let blocks = data.len().div_ceil(block_len);
+    let mut padded;
+    let data = if data.len().is_multiple_of(block_len) {
+        data
+    } else {
+        padded = data.to_vec();
+        padded.resize(blocks * block_len, 0);
+        &padded
+    };
+    let image = SparseImage {
+        block_size,
+        total_blocks,
+        chunks: Vec::new(),
+    };
+    let chunk = Chunk {
+        blocks: blocks as u32,
+        data: ChunkData::Raw(data),
+    };
+    image.encode(start, start + blocks as u32, &[chunk])
+}
+
+/// Checks whether `image` starts with the sparse image magic.
+pub fn is_sparse(image: &[u8]) -> bool {
+    image.len() >= 4 && read_u32(image, 0) == SPARSE_HEADER_MAGIC
+}
+
+impl<'a> SparseImage<'a> {
+    /// Encodes raw `data` as a sparse image, storing blocks that repeat a
+    /// 4-byte value, e.g. zero-filled ones, as FILL chunks.
+    ///
+    /// `data` must be a whole number of blocks.
+    pub fn from_raw(block_size: u32, data: &'a [u8]) -> FbResult<Self> {
+        let block_len = block_size as usize;
+        if block_size == 0 || !block_size.is_multiple_of(4) {
+            return Err(invalid("bad block size"));
+        }
+        if !data.len().is_multiple_of(block_len) {
+            return Err(invalid("raw data isn't a whole number of blocks"));
+        }
+
+        let mut chunks: Vec<Chunk> = Vec::new();
+        for (index, block) in data.chunks(block_len).enumerate() {
+            let offset = index * block_len;
+            let value = read_u32(block, 0);
+            let block_data = if block.chunks(4).all(|word| read_u32(word, 0) == value) {
+                ChunkData::Fill(value)
+            } else {
+                ChunkData::Raw(block)
+            };
+            // Runs of the same kind of blocks share a chunk
+            match (chunks.last_mut(), block_data) {
+                (Some(last), ChunkData::Fill(value)) if last.data == ChunkData::Fill(value) => {
+                    last.blocks += 1
+                }
+                (
+                    Some(Chunk {
+                        blocks,
+                        data: ChunkData::Raw(raw),
+                    }),
+                    ChunkData::Raw(_),
+                ) => {
+                    *raw = &data[offset - raw.len()..offset + block_len];
+                    *blocks += 1;
+                }
+                (_, block_data) => chunks.push(Chunk {
+                    blocks: 1,
+                    data: block_data,
+                }),
+            }
+        }
+
+        Ok(SparseImage {
+            block_size,
+            total_blocks: (data.len() / block_len) as u32,
+            chunks,
+        })
+    }
+
+    /// Parses a sparse image header and all of its chunks.
+    pub fn parse(image: &'a [u8]) -> FbResult<Self> {
+        if image.len() < SPARSE_HEADER_LEN || !is_sparse(image) {
+            return Err(invalid("bad magic"));
+        }
+        if read_u16(image, 4) != 1 {
+            return Err(invalid("unsupported major version"));
+        }
+        let header_len = read_u16(image, 8) as usize;
+        let chunk_header_len = read_u16(image, 10) as usize;
+        if header_len < SPARSE_HEADER_LEN || chunk_header_len < CHUNK_HEADER_LEN {
+            return Err(invalid("bad header size"));
+        }
+        let block_size = read_u32(image, 12);
+        if block_size == 0 || !block_size.is_multiple_of(4) {
+            return Err(invalid("bad block size"));
+        }
+        let total_blocks = read_u32(image, 16);
+        let total_chunks = read_u32(image, 20);
+
+        let mut chunks = Vec::with_capacity(total_chunks as usize);
+        let mut offset = header_len;
+        for _ in 0..total_chunks {
+            if image.len() < offset + chunk_header_len {
+                return Err(invalid("truncated chunk header"));
+            }
+            let chunk_type = read_u16(image, offset);
+            let blocks = read_u32(image, offset + 4);
+            let total_len = read_u32(image, offset + 8) as usize;
+            if total_len < chunk_header_len || image.len() < offset + total_len {
+                return Err(invalid("truncated chunk"));
+            }
+            let body = &image[offset + chunk_header_len..offset + total_len];
+            let data = match chunk_type {
+                CHUNK_TYPE_RAW => {
+                    if body.len() as u64 != u64::from(blocks) * u64::from(block_size) {
+                        return Err(invalid("bad RAW chunk size"));
+                    }
+                    ChunkData::Raw(body)
+                }
+                CHUNK_TYPE_FILL if body.len() == 4 => ChunkData::Fill(read_u32(body, 0)),
+                CHUNK_TYPE_DONT_CARE if body.is_empty() => ChunkData::DontCare,
+                CHUNK_TYPE_CRC32 if body.len() == 4 => ChunkData::Crc32(read_u32(body, 0)),
+                _ => return Err(invalid("bad chunk")),
+            };
+            chunks.push(Chunk { blocks, data });
+            offset += total_len;
+        }
+
+        let blocks: u64 = chunks.iter().map(|chunk| u64::from(chunk.blocks)).sum();
+        if blocks != u64::from(total_blocks) {
+            return Err(invalid("chunks don't match total block count"));
+        }
+
+        Ok(SparseImage {
+            block_size,
+            total_blocks,
+            chunks,
+        })
+    }
+
+    /// Splits the image into sparse sub-images no larger than `max_size` bytes.
+    ///
+    /// Every sub-image describes the whole partition, skipping the blocks
+    /// written by the others, so they can be flashed one after another.
+    pub fn split(&self, max_size: usize) -> FbResult<Vec<Vec<u8>>> {
+        let overhead = SPLIT_OVERHEAD;
+        let block_size = self.block_size as usize;
+        if max_size < overhead + CHUNK_HEADER_LEN + block_size {
+            return Err(FastbootError::Protocol(format!(
+                "Download size {} is too small for sparse images",
+                max_size
+            )));
+        }
+
+        let mut images = Vec::new();
+        let mut pending: Vec<Chunk> = Vec::new();
+        let mut start = 0;
+        let mut block = 0;
+        let mut size = overhead;
+        for chunk in &self.chunks {
+            let mut chunk = chunk.clone();
+            loop {
+                let cost = match chunk.data {
+                    ChunkData::Raw(data) => CHUNK_HEADER_LEN + data.len(),
+                    ChunkData::Fill(_) => CHUNK_HEADER_LEN + 4,
+                    ChunkData::DontCare => CHUNK_HEADER_LEN,
+                    // Checksums can't be kept once the image is split
+                    ChunkData::Crc32(_) => break,
+                };
+                if size + cost <= max_size {
+                    size += cost;
+                    block += chunk.blocks;
+                    pending.push(chunk);
+                    break;
+                }
+
+                // Take as many RAW blocks as possible, the rest goes to the next image
+                if let ChunkData::Raw(data) = chunk.data {
+                    let fit = (max_size - size).saturating_sub(CHUNK_HEADER_LEN) / block_size;
+                    if fit > 0 {
+                        let (head, tail) = data.split_at(fit * block_size);
+                        pending.push(Chunk {
+                            blocks: fit as u32,
+                            data: ChunkData::Raw(head),
+                        });
+                        block += fit as u32;
+                        chunk = Chunk {
+                            blocks: chunk.blocks - fit as u32,
+                            data: ChunkData::Raw(tail),
+                        };
+                    }
+                }
+                images.push(self.encode(start, block, &pending));
+                pending.clear();
+                start = block;
+                size = overhead;
+            }
+        }
+        if !pending.is_empty() || images.is_empty() {
+            images.push(self.encode(start, block, &pending));
+        }
+
+        Ok(images)
+    }
+
+    // Encodes chunks covering blocks [start, end) into a sub-image that spans
+    // the whole partition.
+    fn encode(&self, start: u32, end: u32, chunks: &[Chunk]) -> Vec<u8> {
+        let mut all = Vec::with_capacity(chunks.len() + 2);
+        if start > 0 {
+            all.push(Chunk {
+                blocks: start,
+                data: ChunkData::DontCare,
+            });
+        }
+        all.extend_from_slice(chunks);
+        if end < self.total_blocks {
+            all.push(Chunk {
+                blocks: self.total_blocks - end,
+                data: ChunkData::DontCare,
+            });
+        }
+
+        let mut image = Vec::new();
+        image.extend_from_slice(&SPARSE_HEADER_MAGIC.to_le_bytes());
+        image.extend_from_slice(&1u16.to_le_bytes());
+        image.extend_from_slice(&0u16.to_le_bytes());
+        image.extend_from_slice(&(SPARSE_HEADER_LEN as u16).to_le_bytes());
+        image.extend_from_slice(&(CHUNK_HEADER_LEN as u16).to_le_bytes());
+        image.extend_from_slice(&self.block_size.to_le_bytes());
+        image.extend_from_slice(&self.total_blocks.to_le_bytes());
+        image.extend_from_slice(&(all.len() as u32).to_le_bytes());
+        image.extend_from_slice(&0u32.to_le_bytes());
+        for chunk in &all {
+            let (chunk_type, body_len) = match chunk.data {
+                ChunkData::Raw(data) => (CHUNK_TYPE_RAW, data.len()),
+                ChunkData::Fill(_) => (CHUNK_TYPE_FILL, 4),
+                ChunkData::DontCare => (CHUNK_TYPE_DONT_CARE, 0),
+                ChunkData::Crc32(_) => (CHUNK_TYPE_CRC32, 4),
+            };
+            image.extend_from_slice(&chunk_type.to_le_bytes());
+            image.extend_from_slice(&0u16.to_le_bytes());
+            image.extend_from_slice(&chunk.blocks.to_le_bytes());
+            image.extend_from_slice(&((CHUNK_HEADER_LEN + body_len) as u32).to_le_bytes());
+            match chunk.data {
+                ChunkData::Raw(data) => image.extend_from_slice(data),
+                ChunkData::Fill(value) | ChunkData::Crc32(value) => {
+                    image.extend_from_slice(&value.to_le_bytes())
+                }
+                ChunkData::DontCare => (),
+            }
+        }
+        image
+    }
+}
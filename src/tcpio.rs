@@ -0,0 +1,81 @@
+//! Fastboot over TCP, as used by emulators and network-attached devices.
+//!
+//! A session starts with both sides exchanging a `FB<two-digit version>`
+//! handshake. After that every message is prefixed by its length, encoded as
+//! an 8-byte big-endian number. Each [`Write::write`] call is sent as a single
+//! message, while [`Read::read`] never reads past the end of the current one,
+//! so a reply is received as a whole by a single read of big enough buffer.
+//!
+//! See system/core/fastboot/tcp.cpp in AOSP.
+
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+
+const HANDSHAKE: &[u8] = b"FB01";
+const LENGTH_LEN: usize = 8;
+
+/// A Fastboot client connected over TCP.
+pub struct TcpDevice {
+    stream: TcpStream,
+    // Bytes left to read of the current incoming message
+    remaining: u64,
+}
+
+impl TcpDevice {
+    /// Connects to a client and performs the version handshake.
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        TcpDevice::from_stream(TcpStream::connect(addr)?)
+    }
+
+    /// Performs the version handshake over an already connected stream.
+    pub fn from_stream(mut stream: TcpStream) -> Result<Self> {
+        stream.write_all(HANDSHAKE)?;
+        let mut handshake = [0; 4];
+        stream.read_exact(&mut handshake)?;
+        let version = std::str::from_utf8(&handshake[2..])
+            .ok()
+            .and_then(|version| version.parse::<u8>().ok());
+        match (&handshake[..2], version) {
+            (b"FB", Some(version)) if version >= 1 => Ok(TcpDevice {
+                stream,
+                remaining: 0,
+            }),
+            _ => Err(Error::new(ErrorKind::InvalidData, "Bad Fastboot handshake")),
+        }
+    }
+}
+
+impl Read for TcpDevice {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        while self.remaining == 0 {
+            let mut length = [0; LENGTH_LEN];
+            self.stream.read_exact(&mut length)?;
+            self.remaining = u64::from_be_bytes(length);
+        }
+
+        let transfer_size = std::cmp::min(self.remaining, buf.len() as u64) as usize;
+        self.stream.read_exact(&mut buf[..transfer_size])?;
+        self.remaining -= transfer_size as u64;
+        Ok(transfer_size)
+    }
+}
+
+impl Write for TcpDevice {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        self.stream.write_all(&(buf.len() as u64).to_be_bytes())?;
+        self.stream.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.stream.flush()
+    }
+}
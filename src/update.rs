@@ -0,0 +1,165 @@
+//! Flashing of Android update packages, as done by `fastboot update`.
+//!
+//! An update package is a zip archive with an image per partition, e.g.
+//! `boot.img` or `system.img`, and an optional `android-info.txt` listing
+//! what a client must report to accept the package, one requirement per line:
+//!
+//! ```text
+//! require board=sailfish|marlin
+//! require version-bootloader=8996-012001-1904111015
+//! ```
+//!
+//! See system/core/fastboot/fastboot.cpp in AOSP.
+
+use std::fs::File;
+use std::io::{Read, Seek};
+use std::path::Path;
+
+use zip::result::ZipError;
+use zip::ZipArchive;
+
+use crate::fastboot::{
+    download_limit, fb_flash_reader, sort_for_flashing, Fastboot, FastbootError, FbResult,
+    BOOTLOADER_IMAGES, IMAGE_SUFFIX,
+};
+
+const ANDROID_INFO: &str = "android-info.txt";
+
+fn zip_error(err: ZipError) -> FastbootError {
+    match err {
+        ZipError::Io(err) => FastbootError::from(err),
+        err => FastbootError::Protocol(format!("Invalid update package: {}", err)),
+    }
+}
+
+/// An opened update package.
+pub struct UpdatePackage<R: Read + Seek> {
+    archive: ZipArchive<R>,
+}
+
+impl UpdatePackage<File> {
+    /// Opens an update package stored in a file.
+    pub fn open(path: &Path) -> FbResult<Self> {
+        UpdatePackage::new(File::open(path)?)
+    }
+}
+
+impl<R: Read + Seek> UpdatePackage<R> {
+    /// Reads the contents of an update package from `reader`.
+    pub fn new(reader: R) -> FbResult<Self> {
+        let archive = ZipArchive::new(reader).map_err(zip_error)?;
+        Ok(UpdatePackage { archive })
+    }
+
+    /// Gets names of partitions that have an image in the package, in the
+    /// order they are flashed by [`flash_all`]: bootloader and radio first,
+    /// then the rest in alphabetical order.
+    ///
+    /// [`flash_all`]: #method.flash_all
+    pub fn partitions(&self) -> Vec<String> {
+        let mut partitions: Vec<String> = self
+            .archive
+            .file_names()
+            .filter(|name| !name.contains('/') && name.ends_with(IMAGE_SUFFIX))
+            .map(|name| name[..name.len() - IMAGE_SUFFIX.len()].to_owned())
+            .collect();
+        sort_for_flashing(&mut partitions);
+        partitions
+    }
+
+    /// Checks that a client meets every requirement in `android-info.txt`.
+    ///
+    /// `board` is checked against the `product` variable, anything else
+    /// against the variable of the same name. Packages without
+    /// `android-info.txt` have no requirements.
+    pub fn check_requirements<T: Fastboot>(&mut self, io: &mut T) -> FbResult<()> {
+        let mut info = String::new();
+        match self.archive.by_name(ANDROID_INFO) {
+            Ok(mut file) => file.read_to_string(&mut info)?,
+            Err(ZipError::FileNotFound) => return Ok(()),
+            Err(err) => return Err(zip_error(err)),
+        };
+
+        let mut product = None;
+        for line in info.lines().map(str::trim) {
+            // Lines like `require-for-product:<product> <requirement>` only
+            // apply to that product
+            let requirement = if let Some(rest) = line.strip_prefix("require-for-product:") {
+                let mut parts = rest.splitn(2, char::is_whitespace);
+                let for_product = parts.next().unwrap_or("");
+                if product.is_none() {
+                    product = Some(io.getvar("product")?);
+                }
+                if product.as_deref() != Some(for_product) {
+                    continue;
+                }
+                parts.next().unwrap_or("")
+            } else if let Some(rest) = line.strip_prefix("require ") {
+                rest
+            } else {
+                continue;
+            };
+
+            let (name, values) = match requirement.find('=') {
+                Some(at) => (requirement[..at].trim(), &requirement[at + 1..]),
+                None => continue,
+            };
+            let var = match name {
+                "board" | "product" => "product",
+                // Tells which optional images apply, not what a client must report
+                "partition-exists" => continue,
+                var => var,
+            };
+            let actual = match io.getvar(var) {
+                Ok(value) => value,
+                Err(FastbootError::Fail(_)) => String::new(),
+                Err(err) => return Err(err),
+            };
+            let matches = |value: &str| match value.strip_suffix('*') {
+                Some(prefix) => actual.starts_with(prefix),
+                None => actual == value,
+            };
+            if !values.split('|').map(str::trim).any(matches) {
+                return Err(FastbootError::Protocol(format!(
+                    "Update package requires {} to be {}, but it's {:?}",
+                    var, values, actual
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Flashes the image of a specified partition.
+    pub fn flash_image<T: Fastboot>(&mut self, io: &mut T, partition: &str) -> FbResult<()> {
+        let image = self
+            .archive
+            .by_name(&format!("{}{}", partition, IMAGE_SUFFIX))
+            .map_err(zip_error)?;
+        let len = image.size() as usize;
+        let limit = download_limit(io)?;
+        fb_flash_reader(io, limit, partition, image, len)
+    }
+
+    /// Flashes every image in the package.
+    ///
+    /// The bootloader and radio images go first, each followed by
+    /// `reboot-bootloader`, so the rest is flashed by the new bootloader.
+    /// Clients drop the connection when they reboot, e.g. USB ones
+    /// re-enumerate, so `io` is replaced by what `reconnect` returns after
+    /// every reboot. It's up to `reconnect` to wait for the client to come
+    /// back, e.g. with `UsbContext::wait_for_device`.
+    pub fn flash_all<T, F>(&mut self, io: &mut T, mut reconnect: F) -> FbResult<()>
+    where
+        T: Fastboot,
+        F: FnMut() -> FbResult<T>,
+    {
+        for partition in self.partitions() {
+            self.flash_image(io, &partition)?;
+            if BOOTLOADER_IMAGES.contains(&partition.as_str()) {
+                io.reboot_bootloader()?;
+                *io = reconnect()?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,10 +1,17 @@
+extern crate libc;
 extern crate libusb;
+extern crate libusb_sys;
 
 use std;
+use std::collections::HashSet;
 use std::io::{Error, ErrorKind, Read, Result, Write};
 use std::option::Option;
-use std::time::Duration;
-use usbio::libusb::{Context, DeviceHandle, Direction, TransferType};
+use std::thread;
+use std::time::{Duration, Instant};
+use usbio::libusb::{
+    Context, Device, DeviceDescriptor, DeviceHandle, Direction, LogLevel, TransferType,
+};
+use usbio::libusb_sys::{libusb_context, libusb_device};
 
 macro_rules! iocall {
     ($ex: expr) => {
@@ -12,73 +19,667 @@ macro_rules! iocall {
     };
 }
 
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+const MAX_RETRIES: u32 = 3;
+const RETRY_BACKOFF: Duration = Duration::from_millis(10);
+const DEFAULT_BULK_TRANSFER_SIZE: usize = 1024 * 1024;
+const DRAIN_TIMEOUT: Duration = Duration::from_millis(10);
+// Standard CLEAR_FEATURE(ENDPOINT_HALT) request addressed to an endpoint
+const CLEAR_HALT_REQUEST_TYPE: u8 = 0x02;
+const CLEAR_FEATURE_REQUEST: u8 = 0x01;
+const ENDPOINT_HALT_FEATURE: u16 = 0;
+// Commands that may keep the device busy for long
+const LONG_COMMANDS: &[&[u8]] = &[b"download:", b"upload", b"fetch:", b"flash:", b"erase:"];
+const REBOOT_BOOTLOADER_CMD: &[u8] = b"reboot-bootloader";
+const REPLY_PREFIXES: &[&[u8]] = &[b"OKAY", b"FAIL", b"INFO", b"TEXT", b"DATA"];
+// Class, subclass and protocol of a fastboot interface
+const FASTBOOT_INTERFACE: (u8, u8, u8) = (0xff, 0x42, 0x03);
+// Hotplug definitions of libusb.h, which the libusb-sys bindings lack
+const LIBUSB_HOTPLUG_EVENT_DEVICE_ARRIVED: libc::c_int = 0x01;
+const LIBUSB_HOTPLUG_NO_FLAGS: libc::c_int = 0;
+const LIBUSB_HOTPLUG_MATCH_ANY: libc::c_int = -1;
+
+type HotplugCallbackFn = extern "C" fn(
+    *mut libusb_context,
+    *mut libusb_device,
+    libc::c_int,
+    *mut libc::c_void,
+) -> libc::c_int;
+
+extern "C" {
+    fn libusb_hotplug_register_callback(
+        context: *mut libusb_context,
+        events: libc::c_int,
+        flags: libc::c_int,
+        vendor_id: libc::c_int,
+        product_id: libc::c_int,
+        dev_class: libc::c_int,
+        cb_fn: HotplugCallbackFn,
+        user_data: *mut libc::c_void,
+        handle: *mut libc::c_int,
+    ) -> libc::c_int;
+    fn libusb_hotplug_deregister_callback(context: *mut libusb_context, handle: libc::c_int);
+}
+
 pub struct UsbContext {
     context: Context,
+    timeout: Duration,
+}
+
+pub struct UsbContextBuilder {
+    timeout: Duration,
+    log_level: LogLevel,
+}
+
+impl UsbContextBuilder {
+    // Timeout of every transfer of devices opened through the context
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    // libusb verbosity, from 0 (nothing, the default) to 4 (debug messages)
+    pub fn debug_level(mut self, level: u8) -> Self {
+        self.log_level = match level {
+            0 => LogLevel::None,
+            1 => LogLevel::Error,
+            2 => LogLevel::Warning,
+            3 => LogLevel::Info,
+            _ => LogLevel::Debug,
+        };
+        self
+    }
+
+    pub fn build(self) -> Result<UsbContext> {
+        let mut context = iocall!(Context::new())?;
+        context.set_log_level(self.log_level);
+        Ok(UsbContext {
+            context: context,
+            timeout: self.timeout,
+        })
+    }
 }
 
 impl UsbContext {
+    // Panics if libusb can't be initialized, use `builder()` to handle that
     pub fn new() -> Self {
-        UsbContext {
-            context: Context::new().expect("libusb_init"),
+        UsbContext::builder().build().expect("libusb_init")
+    }
+
+    pub fn builder() -> UsbContextBuilder {
+        UsbContextBuilder {
+            timeout: DEFAULT_TIMEOUT,
+            log_level: LogLevel::None,
         }
     }
 
     pub fn open(&self, vid: u16, pid: u16) -> Result<UsbDevice> {
-        let mut handle = None;
-        let mut e_in = None;
-        let mut e_out = None;
+        self.open_with_timeout(vid, pid, self.timeout)
+    }
 
-        for device in iocall!(self.context.devices())?.iter() {
-            let device_desc = iocall!(device.device_descriptor())?;
-            if device_desc.vendor_id() == vid && device_desc.product_id() == pid {
-                handle = Some(iocall!(device.open())?);
-                let config_desc = iocall!(device.active_config_descriptor())?;
-                for interface in config_desc.interfaces() {
-                    for interface_desc in interface.descriptors() {
-                        for endpoint_desc in interface_desc.endpoint_descriptors() {
-                            if endpoint_desc.direction() == Direction::In
-                                && endpoint_desc.transfer_type() == TransferType::Bulk
-                            {
-                                e_in = Some(Endpoint {
-                                    iface: interface_desc.interface_number(),
-                                    address: endpoint_desc.address(),
-                                    max_packet_size: endpoint_desc.max_packet_size(),
-                                });
-                            }
-                            if endpoint_desc.direction() == Direction::Out
-                                && endpoint_desc.transfer_type() == TransferType::Bulk
-                            {
-                                e_out = Some(Endpoint {
-                                    iface: interface_desc.interface_number(),
-                                    address: endpoint_desc.address(),
-                                    max_packet_size: endpoint_desc.max_packet_size(),
-                                });
-                            }
+    pub fn open_with_timeout(&self, vid: u16, pid: u16, timeout: Duration) -> Result<UsbDevice> {
+        self.open_matching(vid, pid, None, None, timeout, false)
+    }
+
+    // Uses the bulk endpoints of a specific interface, for composite devices
+    // where the first bulk IN/OUT pair belongs to something else, e.g. ADB
+    pub fn open_interface(&self, vid: u16, pid: u16, iface: u8) -> Result<UsbDevice> {
+        self.open_matching(vid, pid, None, Some(iface), self.timeout, false)
+    }
+
+    // Resets the device and detaches kernel drivers before claiming, which
+    // frees interfaces left busy by an aborted session
+    pub fn open_with_reset(&self, vid: u16, pid: u16) -> Result<UsbDevice> {
+        self.open_matching(vid, pid, None, None, self.timeout, true)
+    }
+
+    // Opens the device plugged in at a specific bus and address, which tells
+    // apart identical devices that have no serial number
+    pub fn open_at(&self, vid: u16, pid: u16, bus: u8, address: u8) -> Result<UsbDevice> {
+        self.open_matching(vid, pid, Some((bus, address)), None, self.timeout, false)
+    }
+
+    // Keeps trying to open the device until it succeeds or `timeout` expires,
+    // e.g. while the device re-enumerates after a reboot
+    pub fn wait_for_device(&self, vid: u16, pid: u16, timeout: Duration) -> Result<UsbDevice> {
+        retry_open(timeout, || self.open(vid, pid))
+    }
+
+    // Calls `callback` with every matching device plugged in from now on,
+    // until it returns false or `timeout` expires. Arrivals are reported by
+    // a libusb hotplug callback rather than polled, so this fails with
+    // ErrorKind::Other where libusb has no hotplug support, e.g. on Windows
+    pub fn on_hotplug<'a, F>(
+        &'a self,
+        vid: u16,
+        pid: u16,
+        timeout: Duration,
+        mut callback: F,
+    ) -> Result<()>
+    where
+        F: FnMut(UsbDevice<'a>) -> bool,
+    {
+        if !self.context.has_hotplug() {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "libusb has no hotplug support on this platform",
+            ));
+        }
+
+        let deadline = Instant::now() + timeout;
+        let mut watch = HotplugWatch::register(vid, pid)?;
+        // Devices that arrived but couldn't be opened yet, e.g. until udev
+        // rules apply, which are tried again every POLL_INTERVAL
+        let mut retries = Vec::new();
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(());
+            }
+            let wait = if retries.is_empty() {
+                deadline - now
+            } else {
+                std::cmp::min(POLL_INTERVAL, deadline - now)
+            };
+            let mut locations = watch.wait(wait)?;
+            locations.append(&mut retries);
+            for (bus, address) in locations {
+                match self.open_at(vid, pid, bus, address) {
+                    Ok(device) => {
+                        if !callback(device) {
+                            return Ok(());
                         }
                     }
+                    // Not in Fastboot mode, so it won't be once opened later
+                    Err(ref err) if err.kind() == ErrorKind::InvalidInput => (),
+                    Err(_) => retries.push((bus, address)),
                 }
             }
         }
+    }
+
+    fn locations(&self, vid: u16, pid: u16) -> Result<HashSet<(u8, u8)>> {
+        let mut locations = HashSet::new();
+        for device in iocall!(self.context.devices())?.iter() {
+            let device_desc = iocall!(device.device_descriptor())?;
+            if device_desc.vendor_id() == vid && device_desc.product_id() == pid {
+                locations.insert((device.bus_number(), device.address()));
+            }
+        }
+        Ok(locations)
+    }
 
-        if let (Some(mut handle), Some(e_in), Some(e_out)) = (handle, e_in, e_out) {
-            iocall!(handle.claim_interface(e_in.iface))?;
-            iocall!(handle.claim_interface(e_out.iface))?;
+    fn open_matching(
+        &self,
+        vid: u16,
+        pid: u16,
+        location: Option<(u8, u8)>,
+        iface: Option<u8>,
+        timeout: Duration,
+        reset: bool,
+    ) -> Result<UsbDevice> {
+        let mut present = false;
+        for device in iocall!(self.context.devices())?.iter() {
+            let device_desc = iocall!(device.device_descriptor())?;
+            let at_location = location.map_or(true, |location| {
+                location == (device.bus_number(), device.address())
+            });
+            if device_desc.vendor_id() == vid && device_desc.product_id() == pid && at_location {
+                present = true;
+                if let Some((e_in, e_out)) = find_endpoints(&device, iface)? {
+                    let mut handle = iocall!(device.open())?;
+                    let detached = if reset {
+                        release_kernel(&mut handle, &e_in, &e_out)?
+                    } else {
+                        Vec::new()
+                    };
+                    let mut usb_device =
+                        claim(handle, &device, &device_desc, e_in, e_out, timeout)?;
+                    usb_device.detached = detached;
+                    return Ok(usb_device);
+                }
+            }
+        }
 
-            Ok(UsbDevice {
-                handle: handle,
-                e_in: e_in,
-                e_out: e_out,
-                tx_done_cb: None,
-                timeout: Duration::from_secs(1),
-            })
+        // A device without a bulk IN/OUT pair is most likely in another mode,
+        // e.g. ADB, so tell that apart from the device not being plugged in
+        if present {
+            Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("{:04x}:{:04x} has no Fastboot interface", vid, pid),
+            ))
         } else {
-            Err(Error::from(ErrorKind::NotFound))
+            Err(Error::new(
+                ErrorKind::NotFound,
+                format!("{:04x}:{:04x} is not present", vid, pid),
+            ))
+        }
+    }
+
+    pub fn open_by_serial(&self, serial: &str) -> Result<UsbDevice> {
+        for device in iocall!(self.context.devices())?.iter() {
+            // A device with an unreadable configuration, e.g. an unconfigured
+            // hub, is skipped rather than failing the search
+            if let Ok(Some((e_in, e_out))) = find_endpoints(&device, None) {
+                let device_desc = iocall!(device.device_descriptor())?;
+                // Devices we can't access can't be the ones we are looking for
+                let handle = match device.open() {
+                    Ok(handle) => handle,
+                    Err(_) => continue,
+                };
+                if read_serial_number(&handle, &device_desc).as_deref() == Some(serial) {
+                    return claim(handle, &device, &device_desc, e_in, e_out, self.timeout);
+                }
+            }
+        }
+
+        Err(Error::from(ErrorKind::NotFound))
+    }
+
+    // Tells whether the device could be opened and claimed, without keeping
+    // it open. Meant to point users at the fix, e.g. installing WinUSB with
+    // Zadig on Windows or adding udev rules on Linux, rather than showing a
+    // bare libusb error
+    pub fn check_driver(&self, vid: u16, pid: u16) -> Result<DriverStatus> {
+        for device in iocall!(self.context.devices())?.iter() {
+            let device_desc = iocall!(device.device_descriptor())?;
+            if device_desc.vendor_id() != vid || device_desc.product_id() != pid {
+                continue;
+            }
+            // An unreadable configuration may well be of another device with
+            // the same IDs, e.g. one that isn't configured yet
+            let (e_in, _) = match find_endpoints(&device, None) {
+                Ok(Some(endpoints)) => endpoints,
+                _ => continue,
+            };
+
+            let mut handle = match device.open() {
+                Ok(handle) => handle,
+                Err(err) => return Ok(driver_status(err, None)),
+            };
+            return match handle.claim_interface(e_in.iface) {
+                Ok(()) => Ok(DriverStatus::Ok),
+                Err(err) => Ok(driver_status(err, Some((&handle, e_in.iface)))),
+            };
+        }
+
+        Err(Error::new(
+            ErrorKind::NotFound,
+            format!("{:04x}:{:04x} is not present", vid, pid),
+        ))
+    }
+
+    pub fn list_devices(&self) -> Result<Vec<DeviceInfo>> {
+        let mut devices = Vec::new();
+        for device in iocall!(self.context.devices())?.iter() {
+            // Some devices on the bus have no readable configuration, e.g.
+            // unconfigured hubs, which shouldn't hide the others
+            let e_in = match find_endpoints(&device, None) {
+                Ok(Some((e_in, _))) => e_in,
+                _ => continue,
+            };
+
+            let device_desc = iocall!(device.device_descriptor())?;
+            // Reading strings requires an access to the device, which may not
+            // be granted, so don't fail if they're not readable
+            let handle = device.open().ok();
+            let serial_number = handle
+                .as_ref()
+                .and_then(|handle| read_serial_number(handle, &device_desc));
+            let mode = handle.as_ref().map_or(FastbootMode::Unknown, |handle| {
+                read_mode(handle, &device, &device_desc, &e_in)
+            });
+            devices.push(DeviceInfo {
+                vendor_id: device_desc.vendor_id(),
+                product_id: device_desc.product_id(),
+                bus_number: device.bus_number(),
+                address: device.address(),
+                serial_number: serial_number,
+                mode: mode,
+            });
+        }
+
+        Ok(devices)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DriverStatus {
+    Ok,
+    // Another driver owns the device, e.g. anything but WinUSB on Windows
+    WrongDriver,
+    // The device isn't accessible to the user, e.g. without udev rules
+    NoPermission,
+    Unknown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FastbootMode {
+    Bootloader,
+    // Userspace Fastboot of Android, needed for dynamic partitions
+    Fastbootd,
+    Unknown,
+}
+
+#[derive(Debug, Clone)]
+pub struct DeviceInfo {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub bus_number: u8,
+    pub address: u8,
+    pub serial_number: Option<String>,
+    // A best-effort guess, getvar is-userspace tells for sure
+    pub mode: FastbootMode,
+}
+
+impl DeviceInfo {
+    pub fn is_fastbootd(&self) -> bool {
+        self.mode == FastbootMode::Fastbootd
+    }
+}
+
+// A hotplug callback registered on a libusb context of its own, as the
+// bindings don't expose the one of UsbContext. It only records where devices
+// arrived, as libusb forbids transfers from within the callback
+struct HotplugWatch {
+    context: *mut libusb_context,
+    handle: libc::c_int,
+    // Owned by the watch, the callback writes to it while events are handled
+    arrived: *mut Vec<(u8, u8)>,
+}
+
+impl HotplugWatch {
+    fn register(vid: u16, pid: u16) -> Result<Self> {
+        let mut context = std::ptr::null_mut();
+        libusb_result(unsafe { libusb_sys::libusb_init(&mut context) })?;
+        let arrived = Box::into_raw(Box::<Vec<(u8, u8)>>::default());
+        let mut handle = 0;
+        let result = unsafe {
+            libusb_hotplug_register_callback(
+                context,
+                LIBUSB_HOTPLUG_EVENT_DEVICE_ARRIVED,
+                LIBUSB_HOTPLUG_NO_FLAGS,
+                vid as libc::c_int,
+                pid as libc::c_int,
+                LIBUSB_HOTPLUG_MATCH_ANY,
+                hotplug_arrived,
+                arrived as *mut libc::c_void,
+                &mut handle,
+            )
+        };
+        if let Err(err) = libusb_result(result) {
+            unsafe {
+                libusb_sys::libusb_exit(context);
+                drop(Box::from_raw(arrived));
+            }
+            return Err(err);
+        }
+
+        Ok(HotplugWatch {
+            context: context,
+            handle: handle,
+            arrived: arrived,
+        })
+    }
+
+    // Handles libusb events for up to `timeout`, returning the locations of
+    // the devices that arrived meanwhile
+    fn wait(&mut self, timeout: Duration) -> Result<Vec<(u8, u8)>> {
+        let tv = libc::timeval {
+            tv_sec: timeout.as_secs() as _,
+            tv_usec: timeout.subsec_micros() as _,
+        };
+        let result = unsafe {
+            libusb_sys::libusb_handle_events_timeout_completed(
+                self.context,
+                &tv,
+                std::ptr::null_mut(),
+            )
+        };
+        match result {
+            libusb_sys::LIBUSB_ERROR_INTERRUPTED => (),
+            result => libusb_result(result)?,
+        }
+        Ok(std::mem::take(unsafe { &mut *self.arrived }))
+    }
+}
+
+impl Drop for HotplugWatch {
+    fn drop(&mut self) {
+        unsafe {
+            libusb_hotplug_deregister_callback(self.context, self.handle);
+            libusb_sys::libusb_exit(self.context);
+            drop(Box::from_raw(self.arrived));
+        }
+    }
+}
+
+extern "C" fn hotplug_arrived(
+    _context: *mut libusb_context,
+    device: *mut libusb_device,
+    _event: libc::c_int,
+    user_data: *mut libc::c_void,
+) -> libc::c_int {
+    let arrived = unsafe { &mut *(user_data as *mut Vec<(u8, u8)>) };
+    unsafe {
+        arrived.push((
+            libusb_sys::libusb_get_bus_number(device),
+            libusb_sys::libusb_get_device_address(device),
+        ));
+    }
+    // Stays registered until the watch is dropped
+    0
+}
+
+fn libusb_result(result: libc::c_int) -> Result<()> {
+    if result < 0 {
+        Err(Error::new(
+            ErrorKind::Other,
+            format!("libusb call failed with {}", result),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+// Keeps calling `open` until it succeeds or `timeout` expires
+fn retry_open<'a, F>(timeout: Duration, mut open: F) -> Result<UsbDevice<'a>>
+where
+    F: FnMut() -> Result<UsbDevice<'a>>,
+{
+    let deadline = Instant::now() + timeout;
+    loop {
+        let err = match open() {
+            Ok(device) => return Ok(device),
+            Err(err) => err,
+        };
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(err);
+        }
+        thread::sleep(std::cmp::min(POLL_INTERVAL, deadline - now));
+    }
+}
+
+// libusb can't open devices bound to drivers it has no backend for, which is
+// what happens on Windows without WinUSB. Elsewhere a busy interface is only
+// a driver problem if it's bound to a kernel driver, rather than claimed by
+// another program
+fn driver_status(err: libusb::Error, claimed: Option<(&DeviceHandle, u8)>) -> DriverStatus {
+    match err {
+        libusb::Error::Access => DriverStatus::NoPermission,
+        libusb::Error::NotSupported => DriverStatus::WrongDriver,
+        libusb::Error::Busy => match claimed {
+            Some((handle, iface)) if handle.kernel_driver_active(iface).unwrap_or(false) => {
+                DriverStatus::WrongDriver
+            }
+            _ => DriverStatus::Unknown,
+        },
+        _ => DriverStatus::Unknown,
+    }
+}
+
+// Looks for an interface setting with a pair of bulk IN and OUT endpoints,
+// only considering interface `iface` if it's given. Composite devices may
+// have other bulk pairs, e.g. for a debug UART, so a fastboot interface is
+// preferred and the first pair is only used if there's none
+fn find_endpoints(device: &Device, iface: Option<u8>) -> Result<Option<(Endpoint, Endpoint)>> {
+    let config_desc = iocall!(device.active_config_descriptor())?;
+    let mut first = None;
+    for interface in config_desc.interfaces() {
+        for interface_desc in interface.descriptors() {
+            if iface.map_or(false, |iface| iface != interface_desc.interface_number()) {
+                continue;
+            }
+
+            let mut e_in = None;
+            let mut e_out = None;
+            for endpoint_desc in interface_desc.endpoint_descriptors() {
+                if endpoint_desc.transfer_type() != TransferType::Bulk {
+                    continue;
+                }
+                let endpoint = Endpoint {
+                    iface: interface_desc.interface_number(),
+                    setting: interface_desc.setting_number(),
+                    address: endpoint_desc.address(),
+                    max_packet_size: endpoint_desc.max_packet_size(),
+                };
+                match endpoint_desc.direction() {
+                    Direction::In => e_in = e_in.or(Some(endpoint)),
+                    Direction::Out => e_out = e_out.or(Some(endpoint)),
+                }
+            }
+
+            if let (Some(e_in), Some(e_out)) = (e_in, e_out) {
+                let class = (
+                    interface_desc.class_code(),
+                    interface_desc.sub_class_code(),
+                    interface_desc.protocol_code(),
+                );
+                if class == FASTBOOT_INTERFACE {
+                    return Ok(Some((e_in, e_out)));
+                }
+                first = first.or(Some((e_in, e_out)));
+            }
+        }
+    }
+
+    Ok(first)
+}
+
+fn claim<'a>(
+    mut handle: DeviceHandle<'a>,
+    device: &Device,
+    device_desc: &DeviceDescriptor,
+    e_in: Endpoint,
+    e_out: Endpoint,
+    timeout: Duration,
+) -> Result<UsbDevice<'a>> {
+    let iface = e_in.iface;
+    iocall!(handle.claim_interface(iface))?;
+    if e_in.setting != 0 {
+        iocall!(handle.set_alternate_setting(iface, e_in.setting))?;
+    }
+
+    // Strings are read once, so they can be logged without any transfers
+    let serial_number = read_serial_number(&handle, device_desc);
+    let product = read_product(&handle, device_desc);
+    Ok(UsbDevice {
+        handle: handle,
+        e_in: e_in,
+        e_out: e_out,
+        tx_done_cb: None,
+        command_timeout: timeout,
+        data_timeout: timeout,
+        long_operation: false,
+        data_remaining: 0,
+        bulk_transfer_size: DEFAULT_BULK_TRANSFER_SIZE,
+        zlp_pending: false,
+        pending: Vec::new(),
+        serial_number: serial_number,
+        product: product,
+        origin: Some(Origin::of(device, device_desc)),
+        detached: Vec::new(),
+        claimed: Some(iface),
+    })
+}
+
+// Returns the interfaces kernel drivers were detached from
+fn release_kernel(handle: &mut DeviceHandle, e_in: &Endpoint, e_out: &Endpoint) -> Result<Vec<u8>> {
+    iocall!(handle.reset())?;
+    let mut detached = Vec::new();
+    for iface in [e_in.iface, e_out.iface].iter() {
+        // Not every platform can tell whether a kernel driver is attached
+        if handle.kernel_driver_active(*iface).unwrap_or(false) {
+            iocall!(handle.detach_kernel_driver(*iface))?;
+            detached.push(*iface);
+        }
+    }
+    Ok(detached)
+}
+
+fn read_serial_number(handle: &DeviceHandle, device_desc: &DeviceDescriptor) -> Option<String> {
+    let language = *handle.read_languages(DEFAULT_TIMEOUT).ok()?.first()?;
+    handle
+        .read_serial_number_string(language, device_desc, DEFAULT_TIMEOUT)
+        .ok()
+}
+
+fn read_product(handle: &DeviceHandle, device_desc: &DeviceDescriptor) -> Option<String> {
+    let language = *handle.read_languages(DEFAULT_TIMEOUT).ok()?.first()?;
+    handle
+        .read_product_string(language, device_desc, DEFAULT_TIMEOUT)
+        .ok()
+}
+
+// Guesses the mode from the product string and the name of the interface of
+// `endpoint`. Most devices name the interface just "fastboot" in both modes,
+// so only an explicit mention of either mode tells them apart
+fn read_mode(
+    handle: &DeviceHandle,
+    device: &Device,
+    device_desc: &DeviceDescriptor,
+    endpoint: &Endpoint,
+) -> FastbootMode {
+    let language = match handle.read_languages(DEFAULT_TIMEOUT) {
+        Ok(ref languages) if !languages.is_empty() => languages[0],
+        _ => return FastbootMode::Unknown,
+    };
+
+    let mut names = Vec::new();
+    if let Ok(product) = handle.read_product_string(language, device_desc, DEFAULT_TIMEOUT) {
+        names.push(product);
+    }
+    if let Ok(config_desc) = device.active_config_descriptor() {
+        for interface in config_desc.interfaces() {
+            for interface_desc in interface.descriptors() {
+                if interface_desc.interface_number() == endpoint.iface
+                    && interface_desc.setting_number() == endpoint.setting
+                {
+                    if let Ok(name) =
+                        handle.read_interface_string(language, &interface_desc, DEFAULT_TIMEOUT)
+                    {
+                        names.push(name);
+                    }
+                }
+            }
         }
     }
+
+    let names = names.join(" ").to_lowercase();
+    if names.contains("fastbootd") || names.contains("userspace") {
+        FastbootMode::Fastbootd
+    } else if names.contains("bootloader") {
+        FastbootMode::Bootloader
+    } else {
+        FastbootMode::Unknown
+    }
 }
 
 struct Endpoint {
     iface: u8,
+    setting: u8,
     address: u8,
     max_packet_size: u16,
 }
@@ -88,13 +689,338 @@ pub struct UsbDevice<'a> {
     e_in: Endpoint,
     e_out: Endpoint,
     tx_done_cb: Option<Box<FnMut(u64) + 'a>>,
-    timeout: std::time::Duration,
+    command_timeout: std::time::Duration,
+    data_timeout: std::time::Duration,
+    // Whether a command that may take long is being executed, see
+    // `track_write()`
+    long_operation: bool,
+    // Bytes left to transfer in the current data phase
+    data_remaining: u64,
+    bulk_transfer_size: usize,
+    // Whether the last transfer ended with a full packet, so it needs a
+    // zero-length packet to be terminated
+    zlp_pending: bool,
+    // A packet read while completing a reply that turned out to start the
+    // next one
+    pending: Vec<u8>,
+    // Unknown for `from_handle()`, like the origin
+    serial_number: Option<String>,
+    product: Option<String>,
+    // Where the device was found, unknown for `from_handle()`
+    origin: Option<Origin>,
+    // Interfaces `open_with_reset()` detached kernel drivers from, which are
+    // attached again on drop
+    detached: Vec<u8>,
+    // The interface claimed when opening, which is released on drop. None
+    // for `from_handle()`, as whoever claimed it there releases it too
+    claimed: Option<u8>,
+}
+
+#[derive(Clone, Copy)]
+struct Origin {
+    vendor_id: u16,
+    product_id: u16,
+    location: (u8, u8),
+}
+
+impl Origin {
+    fn of(device: &Device, device_desc: &DeviceDescriptor) -> Self {
+        Origin {
+            vendor_id: device_desc.vendor_id(),
+            product_id: device_desc.product_id(),
+            location: (device.bus_number(), device.address()),
+        }
+    }
 }
 
 impl<'a> UsbDevice<'a> {
+    // Wraps a handle whose interface has already been opened and claimed
+    // elsewhere, e.g. by an application managing its own libusb context.
+    // The interface and its setting aren't needed for transfers and are
+    // reported as 0. The interface is left claimed on drop
+    pub fn from_handle(
+        handle: DeviceHandle<'a>,
+        e_in_addr: u8,
+        e_out_addr: u8,
+        max_packet_size: u16,
+    ) -> Self {
+        UsbDevice {
+            handle: handle,
+            e_in: Endpoint {
+                iface: 0,
+                setting: 0,
+                address: e_in_addr,
+                max_packet_size: max_packet_size,
+            },
+            e_out: Endpoint {
+                iface: 0,
+                setting: 0,
+                address: e_out_addr,
+                max_packet_size: max_packet_size,
+            },
+            tx_done_cb: None,
+            command_timeout: DEFAULT_TIMEOUT,
+            data_timeout: DEFAULT_TIMEOUT,
+            long_operation: false,
+            data_remaining: 0,
+            bulk_transfer_size: DEFAULT_BULK_TRANSFER_SIZE,
+            zlp_pending: false,
+            pending: Vec::new(),
+            serial_number: None,
+            product: None,
+            origin: None,
+            detached: Vec::new(),
+            claimed: None,
+        }
+    }
+
     pub fn set_tx_done_cb(&mut self, cb: Option<Box<FnMut(u64)>>) {
         self.tx_done_cb = cb;
     }
+
+    // Sets how much data a single libusb transfer carries at most, rounded
+    // down to whole packets. Bigger transfers are faster, while smaller ones
+    // report progress and notice cancellation more often, as both happen
+    // between transfers. Data is never copied, so memory use doesn't change
+    pub fn set_bulk_transfer_size(&mut self, size: usize) {
+        let packet_size = std::cmp::max(self.e_out.max_packet_size as usize, 1);
+        self.bulk_transfer_size = std::cmp::max(size / packet_size, 1) * packet_size;
+    }
+
+    pub fn bulk_transfer_size(&self) -> usize {
+        self.bulk_transfer_size
+    }
+
+    // Sets both the command and the data timeout
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.command_timeout = timeout;
+        self.data_timeout = timeout;
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.command_timeout
+    }
+
+    // Timeout of transfers of status commands, e.g. getvar, which should fail
+    // fast if the device is dead
+    pub fn set_command_timeout(&mut self, timeout: Duration) {
+        self.command_timeout = timeout;
+    }
+
+    pub fn command_timeout(&self) -> Duration {
+        self.command_timeout
+    }
+
+    // Timeout of transfers of commands that may take long, i.e. download,
+    // upload, fetch, flash and erase, including their data phase
+    pub fn set_data_timeout(&mut self, timeout: Duration) {
+        self.data_timeout = timeout;
+    }
+
+    pub fn data_timeout(&self) -> Duration {
+        self.data_timeout
+    }
+
+    // Reboots into the bootloader and opens the device again once it's back,
+    // with the same timeouts. The device may drop off the bus before it
+    // replies, which isn't an error
+    pub fn reboot_and_reconnect<'b>(
+        mut self,
+        context: &'b UsbContext,
+        timeout: Duration,
+    ) -> Result<UsbDevice<'b>> {
+        let origin = self.origin.ok_or_else(|| {
+            Error::new(
+                ErrorKind::Other,
+                "Can't tell which device to wait for after a reboot",
+            )
+        })?;
+        let deadline = Instant::now() + timeout;
+
+        self.write_all(REBOOT_BOOTLOADER_CMD)?;
+        let mut reply = [0; 512];
+        // INFO and TEXT lines may come before the final reply
+        while let Ok(received) = self.read(&mut reply) {
+            let reply = &reply[..received];
+            if reply.starts_with(b"FAIL") {
+                return Err(Error::new(
+                    ErrorKind::Other,
+                    String::from_utf8_lossy(&reply[4..]).into_owned(),
+                ));
+            }
+            if !(reply.starts_with(b"INFO") || reply.starts_with(b"TEXT"))
+                || Instant::now() >= deadline
+            {
+                break;
+            }
+        }
+        let serial_number = self.serial_number.clone();
+        let (command_timeout, data_timeout) = (self.command_timeout, self.data_timeout);
+        drop(self);
+
+        // Until the device is gone, it's the old one that would be opened
+        while context
+            .locations(origin.vendor_id, origin.product_id)?
+            .contains(&origin.location)
+        {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::new(ErrorKind::TimedOut, "The device didn't reboot"));
+            }
+            thread::sleep(std::cmp::min(POLL_INTERVAL, deadline - now));
+        }
+
+        // Another identical device may be plugged in, e.g. on a flashing
+        // station, so the serial number tells which one is ours
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let mut device = match serial_number {
+            Some(ref serial) => retry_open(remaining, || context.open_by_serial(serial))?,
+            None => context.wait_for_device(origin.vendor_id, origin.product_id, remaining)?,
+        };
+        device.command_timeout = command_timeout;
+        device.data_timeout = data_timeout;
+        Ok(device)
+    }
+
+    pub fn serial_number(&self) -> Option<String> {
+        self.serial_number.clone()
+    }
+
+    pub fn product(&self) -> Option<String> {
+        self.product.clone()
+    }
+
+    pub fn in_max_packet_size(&self) -> u16 {
+        self.e_in.max_packet_size
+    }
+
+    pub fn out_max_packet_size(&self) -> u16 {
+        self.e_out.max_packet_size
+    }
+
+    pub fn in_address(&self) -> u8 {
+        self.e_in.address
+    }
+
+    pub fn out_address(&self) -> u8 {
+        self.e_out.address
+    }
+
+    pub fn interface(&self) -> u8 {
+        self.e_in.iface
+    }
+
+    // Reads and discards whatever the device has left to send, e.g. replies
+    // to an aborted command, so they aren't taken for replies to the next one.
+    // Returns how many bytes were discarded
+    pub fn drain(&mut self) -> Result<usize> {
+        let mut buf = vec![0; std::cmp::max(self.e_in.max_packet_size as usize, 1)];
+        let mut discarded = 0;
+        loop {
+            match self
+                .handle
+                .read_bulk(self.e_in.address, &mut buf, DRAIN_TIMEOUT)
+            {
+                Ok(received) => discarded += received,
+                Err(libusb::Error::Timeout) => break,
+                Err(err) => return Err(err_to_io_err(err)),
+            }
+        }
+
+        discarded += self.pending.len();
+        self.pending.clear();
+        self.long_operation = false;
+        self.data_remaining = 0;
+        Ok(discarded)
+    }
+
+    // Clears a stall condition on both endpoints
+    pub fn clear_halt(&mut self) -> Result<()> {
+        let (e_in, e_out) = (self.e_in.address, self.e_out.address);
+        self.clear_endpoint_halt(e_in)?;
+        self.clear_endpoint_halt(e_out)
+    }
+
+    // libusb 0.3 has no binding for libusb_clear_halt(), so send the standard
+    // request it's built on directly
+    fn clear_endpoint_halt(&mut self, address: u8) -> Result<()> {
+        iocall!(self.handle.write_control(
+            CLEAR_HALT_REQUEST_TYPE,
+            CLEAR_FEATURE_REQUEST,
+            ENDPOINT_HALT_FEATURE,
+            address as u16,
+            &[],
+            self.command_timeout
+        ))?;
+        Ok(())
+    }
+
+    // Decides whether a failed transfer on the endpoint at `address` is worth
+    // retrying. Stalls and interruptions are often transient on flaky hubs, so
+    // they are retried a few times with a growing delay, clearing the stall first
+    fn recover(&mut self, err: libusb::Error, address: u8, retries: &mut u32) -> Result<()> {
+        match err {
+            libusb::Error::Pipe | libusb::Error::Interrupted if *retries < MAX_RETRIES => {
+                *retries += 1;
+                thread::sleep(RETRY_BACKOFF * *retries);
+                if let libusb::Error::Pipe = err {
+                    self.clear_endpoint_halt(address)?;
+                }
+                Ok(())
+            }
+            _ => Err(err_to_io_err(err)),
+        }
+    }
+
+    fn transfer_timeout(&self) -> Duration {
+        if self.long_operation {
+            self.data_timeout
+        } else {
+            self.command_timeout
+        }
+    }
+
+    // Transfers carry no hint of what they are for, so follow the protocol:
+    // a long operation starts with one of LONG_COMMANDS and lasts until its
+    // final reply, skipping over the data phase announced by DATA
+    fn track_write(&mut self, sent: &[u8]) {
+        if self.data_remaining > 0 {
+            self.data_remaining = self.data_remaining.saturating_sub(sent.len() as u64);
+        } else if LONG_COMMANDS
+            .iter()
+            .any(|command| sent.starts_with(command))
+        {
+            self.long_operation = true;
+        }
+    }
+
+    fn track_read(&mut self, received: &[u8]) {
+        if self.data_remaining > 0 {
+            self.data_remaining = self.data_remaining.saturating_sub(received.len() as u64);
+        } else if received.starts_with(b"DATA") {
+            self.data_remaining = std::str::from_utf8(&received[4..])
+                .ok()
+                .and_then(|size| u64::from_str_radix(size, 16).ok())
+                .unwrap_or(0);
+        } else if received.starts_with(b"OKAY") || received.starts_with(b"FAIL") {
+            self.long_operation = false;
+        }
+    }
+}
+
+impl<'a> Drop for UsbDevice<'a> {
+    // libusb releases claimed interfaces when the handle is closed, which may
+    // be too late on some platforms, and never attaches kernel drivers again.
+    // Errors are of no use here, e.g. the device may be gone already, and an
+    // interface that's released twice only gets an error
+    fn drop(&mut self) {
+        if let Some(iface) = self.claimed {
+            let _ = self.handle.release_interface(iface);
+        }
+        for iface in &self.detached {
+            let _ = self.handle.attach_kernel_driver(*iface);
+        }
+    }
 }
 
 impl<'a> Read for UsbDevice<'a> {
@@ -103,11 +1029,68 @@ impl<'a> Read for UsbDevice<'a> {
             return Ok(0);
         }
 
-        let transfer_size = std::cmp::min(self.e_in.max_packet_size as usize, buf.len());
-        iocall!(
-            self.handle
-                .read_bulk(self.e_in.address, &mut buf[..transfer_size], self.timeout)
-        )
+        if !self.pending.is_empty() {
+            let len = std::cmp::min(self.pending.len(), buf.len());
+            buf[..len].copy_from_slice(&self.pending[..len]);
+            self.pending.drain(..len);
+            self.track_read(&buf[..len]);
+            return Ok(len);
+        }
+
+        // Data comes in bursts like it's sent, but with no more than what's
+        // left, as a transfer only ends early with a short packet
+        let packet_size = std::cmp::max(self.e_in.max_packet_size as usize, 1);
+        let burst_size = std::cmp::min(buf.len() as u64, self.data_remaining)
+            .min(self.bulk_transfer_size as u64) as usize
+            / packet_size
+            * packet_size;
+        let transfer_size = if burst_size > packet_size {
+            burst_size
+        } else {
+            std::cmp::min(packet_size, buf.len())
+        };
+        let address = self.e_in.address;
+        let timeout = self.transfer_timeout();
+        let mut retries = 0;
+        let mut received = loop {
+            match self
+                .handle
+                .read_bulk(address, &mut buf[..transfer_size], timeout)
+            {
+                Ok(received) => break received,
+                Err(err) => self.recover(err, address, &mut retries)?,
+            }
+        };
+
+        // A reply longer than a packet comes in several, the last of them
+        // short. They follow right away, so a full packet that isn't followed
+        // by another one before DRAIN_TIMEOUT ends the reply
+        if self.data_remaining == 0 {
+            while received > 0 && received % packet_size == 0 && buf.len() - received >= packet_size
+            {
+                let mut packet = vec![0; packet_size];
+                let len = match self.handle.read_bulk(address, &mut packet, DRAIN_TIMEOUT) {
+                    Ok(len) => len,
+                    Err(libusb::Error::Timeout) => break,
+                    Err(err) => return Err(err_to_io_err(err)),
+                };
+                if REPLY_PREFIXES
+                    .iter()
+                    .any(|prefix| packet[..len].starts_with(prefix))
+                {
+                    self.pending.extend_from_slice(&packet[..len]);
+                    break;
+                }
+                buf[received..received + len].copy_from_slice(&packet[..len]);
+                received += len;
+                if len < packet_size {
+                    break;
+                }
+            }
+        }
+
+        self.track_read(&buf[..received]);
+        Ok(received)
     }
 }
 
@@ -117,12 +1100,24 @@ impl<'a> Write for UsbDevice<'a> {
             return Ok(0);
         }
 
-        let transfer_size = std::cmp::min(self.e_out.max_packet_size as usize, buf.len());
-        let transferred = iocall!(self.handle.write_bulk(
-            self.e_out.address,
-            &buf[..transfer_size],
-            self.timeout
-        ))?;
+        let packet_size = std::cmp::max(self.e_out.max_packet_size as usize, 1);
+        let transfer_size = write_transfer_len(buf.len(), packet_size, self.bulk_transfer_size);
+        let address = self.e_out.address;
+        let timeout = self.transfer_timeout();
+        let mut retries = 0;
+        let transferred = loop {
+            match self
+                .handle
+                .write_bulk(address, &buf[..transfer_size], timeout)
+            {
+                Ok(transferred) => break transferred,
+                Err(err) => self.recover(err, address, &mut retries)?,
+            }
+        };
+
+        self.track_write(&buf[..transferred]);
+
+        self.zlp_pending = transferred > 0 && transferred % packet_size == 0;
 
         if let Some(ref mut cb) = self.tx_done_cb {
             cb(transferred as u64);
@@ -130,11 +1125,29 @@ impl<'a> Write for UsbDevice<'a> {
         Ok(transferred as usize)
     }
 
+    // Terminates the current transfer, sending a zero-length packet if the
+    // device can't tell it has ended otherwise
     fn flush(&mut self) -> Result<()> {
+        if self.zlp_pending {
+            let timeout = self.transfer_timeout();
+            iocall!(self.handle.write_bulk(self.e_out.address, &[], timeout))?;
+            self.zlp_pending = false;
+        }
         Ok(())
     }
 }
 
+// Whole packets are sent in bursts of up to `bulk_transfer_size`, as a
+// transfer per packet is far too slow for downloads. A short tail goes
+// separately, so it terminates the transfer
+fn write_transfer_len(len: usize, packet_size: usize, bulk_transfer_size: usize) -> usize {
+    if len > packet_size {
+        std::cmp::min(len, bulk_transfer_size) / packet_size * packet_size
+    } else {
+        len
+    }
+}
+
 fn err_to_io_err(e: libusb::Error) -> Error {
     Error::from(match e {
         libusb::Error::Pipe => ErrorKind::BrokenPipe,
@@ -143,6 +1156,49 @@ fn err_to_io_err(e: libusb::Error) -> Error {
         libusb::Error::Access => ErrorKind::PermissionDenied,
         libusb::Error::Busy => ErrorKind::AddrInUse,
         libusb::Error::NotSupported => ErrorKind::NotFound,
+        libusb::Error::NoDevice => ErrorKind::NotConnected,
         _ => ErrorKind::Other,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{write_transfer_len, DEFAULT_BULK_TRANSFER_SIZE};
+
+    #[test]
+    fn test_write_transfer_len() {
+        // A download of 3.5 MiB and a bit, written the way write_all does
+        let mut remaining = 3 * DEFAULT_BULK_TRANSFER_SIZE + DEFAULT_BULK_TRANSFER_SIZE / 2 + 100;
+        let mut transfers = Vec::new();
+        while remaining > 0 {
+            let len = write_transfer_len(remaining, 512, DEFAULT_BULK_TRANSFER_SIZE);
+            assert!(len > 0 && len <= DEFAULT_BULK_TRANSFER_SIZE);
+            transfers.push(len);
+            remaining -= len;
+        }
+        assert_eq!(
+            vec![
+                DEFAULT_BULK_TRANSFER_SIZE,
+                DEFAULT_BULK_TRANSFER_SIZE,
+                DEFAULT_BULK_TRANSFER_SIZE,
+                DEFAULT_BULK_TRANSFER_SIZE / 2,
+                100,
+            ],
+            transfers
+        );
+        assert!(transfers[..4].iter().all(|len| len % 512 == 0));
+
+        // Anything up to a packet goes as is
+        assert_eq!(
+            512,
+            write_transfer_len(512, 512, DEFAULT_BULK_TRANSFER_SIZE)
+        );
+        assert_eq!(17, write_transfer_len(17, 64, DEFAULT_BULK_TRANSFER_SIZE));
+        // Bursts are rounded down to whole packets
+        assert_eq!(
+            1024,
+            write_transfer_len(1500, 512, DEFAULT_BULK_TRANSFER_SIZE)
+        );
+        assert_eq!(8 * 64, write_transfer_len(4096, 64, 8 * 64 + 10));
+    }
+}